@@ -0,0 +1,68 @@
+use std::f64::consts;
+
+/// Online estimate of how many rating points white is stronger by, from
+/// the results of players with known ratings. Every game is a gradient
+/// step on the log-likelihood, with a step size that starts out like a
+/// running average and settles to a small constant, so it can follow a
+/// drift.
+#[derive(Clone, Debug, Default)]
+pub struct WhiteAdvantage {
+    pub elo: f64,
+    pub games: u64,
+    pub white_score_accum: f64,
+}
+
+impl WhiteAdvantage {
+    // Elo points per unit of logit
+    const SCALE: f64 = 400.0 / consts::LN_10;
+    const MIN_STEP: f64 = 0.5;
+
+    /// Learn from a game between players rated rating_diff apart, from
+    /// white's side, in which white scored score
+    pub fn learn(&mut self, rating_diff: f64, score: f64) {
+        let expected = 1.0 / (1.0 + (-(rating_diff + self.elo) / WhiteAdvantage::SCALE).exp());
+        // Newton's step, with every game so far worth 0.25 / SCALE^2 of
+        // information, plus a few to start with
+        let step = WhiteAdvantage::SCALE / (0.25 * (self.games + 10) as f64);
+        self.elo += step.max(WhiteAdvantage::MIN_STEP) * (score - expected);
+        self.games += 1;
+        self.white_score_accum += score;
+    }
+
+    /// Average score of white
+    pub fn white_score(&self) -> f64 {
+        self.white_score_accum / self.games as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WhiteAdvantage;
+    use significance::Rng;
+
+    #[test]
+    fn converges_to_the_advantage_of_white() {
+        const TRUE_ELO: f64 = 35.0;
+        let mut rng = Rng::new(1);
+        let mut advantage = WhiteAdvantage::default();
+        // It follows the noise of the last games, so average the estimates
+        // once it settled
+        let mut settled = 0.0;
+        for game in 0..50_000 {
+            let rating_diff = rng.below(401) as f64 - 200.0;
+            let expected = 1.0 / (1.0 + (-(rating_diff + TRUE_ELO) / WhiteAdvantage::SCALE).exp());
+            let score = if (rng.below(1_000_000) as f64) < expected * 1e6 {
+                1.0
+            } else {
+                0.0
+            };
+            advantage.learn(rating_diff, score);
+            if game >= 10_000 {
+                settled += advantage.elo / 40_000.0;
+            }
+        }
+        assert!((settled - TRUE_ELO).abs() < 5.0, "{}", settled);
+        assert!(advantage.white_score() > 0.5);
+        assert_eq!(advantage.games, 50_000);
+    }
+}
@@ -0,0 +1,415 @@
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter, SeekFrom};
+use std::path::Path;
+use std::sync::Arc;
+
+use chrono::{TimeZone, Utc};
+use input::{progress_bar, ProgressBarRead};
+use pgn_reader::{Color, Outcome};
+use playerdb::PlayerNames;
+
+use super::{Clock, GameSink, ResultUpdate, TimeControl};
+
+// Cache file layout, all integers little endian:
+//   header: magic, version u32, game count u64, offset of the name table u64
+//   games:  white id u32, black id u32, unix time u32, white elo u16 and
+//           black elo u16 (NO_RATING if unknown or out of range), result
+//           and speed packed in a byte, clock base u32 (NO_CLOCK for
+//           correspondence) and increment u32 in seconds, white and black
+//           rating diff i16 (NO_DIFF if unknown)
+//   names:  count u32, then per name a u16 length and the UTF-8 bytes
+pub const MAGIC: &[u8; 4] = b"RRGC";
+const VERSION: u32 = 4;
+const HEADER_SIZE: u64 = 4 + 4 + 8 + 8;
+const GAME_SIZE: usize = 4 + 4 + 4 + 2 + 2 + 1 + 4 + 4 + 2 + 2;
+const NO_CLOCK: u32 = 0xffff_ffff;
+// For games without a valid rating header, lichess ratings are never 0
+const NO_RATING: u16 = 0;
+
+fn rating(packed: u16) -> Option<i32> {
+    match packed {
+        NO_RATING => None,
+        rating => Some(i32::from(rating)),
+    }
+}
+
+// Rating diffs are a few points, and clamped to the i16 range above it
+const NO_DIFF: i16 = i16::MIN;
+
+fn pack_diff(diff: Option<i32>) -> i16 {
+    diff.map_or(NO_DIFF, |diff| {
+        diff.max(-i32::from(i16::MAX)).min(i32::from(i16::MAX)) as i16
+    })
+}
+
+fn rating_diff(packed: i16) -> Option<i32> {
+    match packed {
+        NO_DIFF => None,
+        diff => Some(i32::from(diff)),
+    }
+}
+
+const SPEEDS: [TimeControl; 5] = [
+    TimeControl::Bullet,
+    TimeControl::Blitz,
+    TimeControl::Rapid,
+    TimeControl::Classical,
+    TimeControl::Correspondence,
+];
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn pack_kind(result: &Outcome, speed: &TimeControl) -> Option<u8> {
+    let result = match result {
+        Outcome::Decisive {
+            winner: Color::White,
+        } => 0,
+        Outcome::Decisive {
+            winner: Color::Black,
+        } => 1,
+        Outcome::Draw => 2,
+    };
+    let speed = SPEEDS.iter().position(|s| s == speed)? as u8;
+    Some(result | (speed << 2))
+}
+
+fn pack_rating(rating: Option<i32>) -> u16 {
+    // A rating of 0 reads back as unknown, which is what PGNs mean by it,
+    // and so do ratings that don't fit
+    rating
+        .and_then(|rating| u16::try_from(rating).ok())
+        .unwrap_or(NO_RATING)
+}
+
+fn unpack_kind(kind: u8) -> io::Result<(Outcome, TimeControl)> {
+    let result = match kind & 3 {
+        0 => Outcome::Decisive {
+            winner: Color::White,
+        },
+        1 => Outcome::Decisive {
+            winner: Color::Black,
+        },
+        2 => Outcome::Draw,
+        _ => return Err(invalid("Bad game result in cache")),
+    };
+    let speed = SPEEDS
+        .get((kind >> 2) as usize)
+        .ok_or_else(|| invalid("Bad time control in cache"))?;
+    Ok((result, speed.clone()))
+}
+
+/// Writes the games it is fed to a cache file. Call finish when done,
+/// the file is not readable before that.
+pub struct CacheWriter {
+    out: BufWriter<File>,
+    names: PlayerNames,
+    game_count: u64,
+    // Games that can't be stored, like those before 1970
+    skipped: u64,
+    // GameSink can't fail, so write errors are reported by finish
+    error: Option<io::Error>,
+}
+
+impl CacheWriter {
+    pub fn create(path: &Path) -> io::Result<CacheWriter> {
+        let mut out = BufWriter::new(File::create(path)?);
+        // Placeholder until the counts are known
+        out.write_all(&[0; HEADER_SIZE as usize])?;
+        Ok(CacheWriter {
+            out,
+            names: PlayerNames::new(),
+            game_count: 0,
+            skipped: 0,
+            error: None,
+        })
+    }
+
+    /// The game as stored in the cache, or None if it has a name, date or
+    /// time control the cache can't hold
+    fn pack_game(&mut self, update: &ResultUpdate) -> Option<[u8; GAME_SIZE]> {
+        for name in [&update.white, &update.black].iter() {
+            if name.len() > usize::from(u16::MAX) {
+                return None;
+            }
+        }
+        // 1970 to 2106
+        let timestamp = u32::try_from(update.datetime.timestamp()).ok()?;
+        let kind = pack_kind(&update.result.unwrap(), &update.speed)?;
+        let white = self.names.intern(&update.white);
+        let black = self.names.intern(&update.black);
+        let mut game = [0u8; GAME_SIZE];
+        game[0..4].copy_from_slice(&white.to_le_bytes());
+        game[4..8].copy_from_slice(&black.to_le_bytes());
+        game[8..12].copy_from_slice(&timestamp.to_le_bytes());
+        game[12..14].copy_from_slice(&pack_rating(update.white_rating).to_le_bytes());
+        game[14..16].copy_from_slice(&pack_rating(update.black_rating).to_le_bytes());
+        game[16] = kind;
+        let (base, increment) = match update.clock {
+            Some(clock) => (clock.base.min(NO_CLOCK - 1), clock.increment),
+            None => (NO_CLOCK, 0),
+        };
+        game[17..21].copy_from_slice(&base.to_le_bytes());
+        game[21..25].copy_from_slice(&increment.to_le_bytes());
+        game[25..27].copy_from_slice(&pack_diff(update.white_rating_diff).to_le_bytes());
+        game[27..29].copy_from_slice(&pack_diff(update.black_rating_diff).to_le_bytes());
+        Some(game)
+    }
+
+    /// Number of games left out because the cache can't hold them
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        let names_offset = HEADER_SIZE + self.game_count * GAME_SIZE as u64;
+        self.out
+            .write_all(&(self.names.len() as u32).to_le_bytes())?;
+        for name in self.names.iter() {
+            self.out.write_all(&(name.len() as u16).to_le_bytes())?;
+            self.out.write_all(name.as_bytes())?;
+        }
+
+        self.out.seek(SeekFrom::Start(0))?;
+        self.out.write_all(MAGIC)?;
+        self.out.write_all(&VERSION.to_le_bytes())?;
+        self.out.write_all(&self.game_count.to_le_bytes())?;
+        self.out.write_all(&names_offset.to_le_bytes())?;
+        self.out.flush()
+    }
+}
+
+impl GameSink for CacheWriter {
+    fn add_game(&mut self, update: ResultUpdate) {
+        if self.error.is_some() {
+            return;
+        }
+        match self.pack_game(&update) {
+            Some(game) => match self.out.write_all(&game) {
+                Ok(()) => self.game_count += 1,
+                Err(e) => self.error = Some(e),
+            },
+            None => self.skipped += 1,
+        }
+    }
+
+    fn status(&self) -> String {
+        format!(", {} players", self.names.len())
+    }
+}
+
+fn read_u16<R: Read>(input: &mut R) -> io::Result<u16> {
+    let mut buf = [0; 2];
+    input.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    input.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    input.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// Feed the games in a cache file with one of the given speeds to the sink
+pub fn process_cache<S: GameSink>(
+    path: &Path,
+    sink: &mut S,
+    speeds: &[TimeControl],
+) -> io::Result<()> {
+    println!("Processing {}", path.display());
+
+    let mut input = BufReader::new(File::open(path)?);
+    let mut magic = [0; 4];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC || read_u32(&mut input)? != VERSION {
+        return Err(invalid("Not a game cache of this version"));
+    }
+    let game_count = read_u64(&mut input)?;
+    let names_offset = read_u64(&mut input)?;
+
+    input.seek(SeekFrom::Start(names_offset))?;
+    let name_count = read_u32(&mut input)?;
+    let mut names: Vec<Arc<str>> = Vec::with_capacity(name_count as usize);
+    for _ in 0..name_count {
+        let mut name = vec![0; read_u16(&mut input)? as usize];
+        input.read_exact(&mut name)?;
+        let name = String::from_utf8(name).map_err(|_| invalid("Bad player name in cache"))?;
+        names.push(name.into());
+    }
+    let name = |id: u32| {
+        names
+            .get(id as usize)
+            .cloned()
+            .ok_or_else(|| invalid("Bad player id in cache"))
+    };
+    sink.start_cache();
+
+    let pb = progress_bar(names_offset);
+    let mut input = input.into_inner();
+    input.seek(SeekFrom::Start(HEADER_SIZE))?;
+    let mut input = BufReader::new(ProgressBarRead::new(&pb, input));
+
+    let mut game = [0u8; GAME_SIZE];
+    for counter in 1..=game_count {
+        if counter.is_multiple_of(10000) {
+            pb.tick();
+            pb.set_message(&format!("{} games{}", counter, sink.status()));
+        }
+
+        input.read_exact(&mut game)?;
+        let (result, speed) = unpack_kind(game[16])?;
+        if !speeds.contains(&speed) {
+            continue;
+        }
+        let mut update = ResultUpdate::new();
+        let white = u32::from_le_bytes([game[0], game[1], game[2], game[3]]);
+        let black = u32::from_le_bytes([game[4], game[5], game[6], game[7]]);
+        update.white = name(white)?;
+        update.black = name(black)?;
+        update.cache_ids = Some((white, black));
+        let timestamp = u32::from_le_bytes([game[8], game[9], game[10], game[11]]);
+        update.datetime = Utc.timestamp(i64::from(timestamp), 0);
+        update.white_rating = rating(u16::from_le_bytes([game[12], game[13]]));
+        update.black_rating = rating(u16::from_le_bytes([game[14], game[15]]));
+        update.white_rating_diff = rating_diff(i16::from_le_bytes([game[25], game[26]]));
+        update.black_rating_diff = rating_diff(i16::from_le_bytes([game[27], game[28]]));
+        update.result = Some(result);
+        update.speed = speed;
+        let base = u32::from_le_bytes([game[17], game[18], game[19], game[20]]);
+        if base != NO_CLOCK {
+            update.clock = Some(Clock {
+                base,
+                increment: u32::from_le_bytes([game[21], game[22], game[23], game[24]]),
+            });
+        }
+        update.rated = true;
+        sink.add_game(update);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use pgn_reader::{Color, Outcome};
+    use std::fs;
+    use std::path::Path;
+
+    use super::{process_cache, CacheWriter, SPEEDS};
+    use testutil::{self, temp_path};
+    use {Clock, GameSink, ResultUpdate, TimeControl};
+
+    fn game(white: &str, black: &str, speed: TimeControl, seconds: i64) -> ResultUpdate {
+        let mut update = testutil::game(white, black, Outcome::Draw, seconds);
+        update.speed = speed;
+        update
+    }
+
+    /// Write the games to a new cache file
+    fn write(path: &Path, games: &[ResultUpdate]) -> std::io::Result<()> {
+        let mut writer = CacheWriter::create(path).unwrap();
+        for game in games {
+            writer.add_game(game.clone());
+        }
+        writer.finish()
+    }
+
+    #[test]
+    fn games_read_back_as_written() {
+        let mut blitz = game("a", "b", TimeControl::Blitz, 1_514_764_800);
+        blitz.result = Some(Outcome::Decisive {
+            winner: Color::Black,
+        });
+        blitz.clock = Some(Clock {
+            base: 180,
+            increment: 2,
+        });
+        blitz.white_rating = Some(1500);
+        blitz.black_rating = Some(2100);
+        blitz.white_rating_diff = Some(-4);
+        blitz.black_rating_diff = Some(3);
+        // Correspondence has no clock, and the ratings are unknown
+        let correspondence = game("b", "c", TimeControl::Correspondence, 1_514_851_200);
+        let mut classical = game("c", "a", TimeControl::Classical, 1_514_937_600);
+        classical.clock = Some(Clock {
+            base: 1800,
+            increment: 0,
+        });
+        classical.white_rating_diff = Some(40_000);
+        classical.black_rating_diff = Some(-40_000);
+        let games = vec![blitz, correspondence, classical];
+
+        let path = temp_path("cache");
+        write(&path, &games).unwrap();
+        let mut read = vec![];
+        let result = process_cache(&path, &mut read, &SPEEDS);
+        fs::remove_file(&path).unwrap();
+        result.unwrap();
+
+        let mut expected = games;
+        for (game, ids) in expected.iter_mut().zip([(0, 1), (1, 2), (2, 0)].iter()) {
+            game.cache_ids = Some(*ids);
+        }
+        // Diffs beyond the i16 range are clamped
+        expected[2].white_rating_diff = Some(i32::from(i16::MAX));
+        expected[2].black_rating_diff = Some(-i32::from(i16::MAX));
+        assert_eq!(read, expected);
+    }
+
+    #[test]
+    fn values_out_of_range_are_not_written() {
+        let mut before_1970 = game("a", "b", TimeControl::Blitz, -1);
+        let mut after_2106 = before_1970.clone();
+        after_2106.datetime = Utc.timestamp(1 << 32, 0);
+        let long_name = "a".repeat(70_000);
+        let mut rating = game("b", "c", TimeControl::Blitz, 0);
+        rating.white_rating = Some(70_000);
+        rating.black_rating = Some(-1);
+        before_1970.white_rating = Some(1500);
+        let games = [
+            before_1970,
+            game("a", "b", TimeControl::Blitz, 0),
+            after_2106,
+            game(&long_name, "b", TimeControl::Blitz, 0),
+            game("a", "b", TimeControl::UltraBullet, 0),
+            rating,
+            game("c", "a", TimeControl::Rapid, 60),
+        ];
+
+        let path = temp_path("cache-range");
+        let mut writer = CacheWriter::create(&path).unwrap();
+        for game in games.iter() {
+            writer.add_game(game.clone());
+        }
+        assert_eq!(writer.skipped(), 4);
+        writer.finish().unwrap();
+        let mut read = vec![];
+        let result = process_cache(&path, &mut read, &SPEEDS);
+        fs::remove_file(&path).unwrap();
+        result.unwrap();
+
+        // The games after a bad one are kept, and ratings that don't fit
+        // are unknown
+        let mut expected = vec![games[1].clone(), games[5].clone(), games[6].clone()];
+        expected[1].white_rating = None;
+        expected[1].black_rating = None;
+        for (game, ids) in expected.iter_mut().zip([(0, 1), (1, 2), (2, 0)].iter()) {
+            game.cache_ids = Some(*ids);
+        }
+        assert_eq!(read, expected);
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+use ratingsystem::{self, Settings, SystemInstance, SystemSpec, SYSTEMS};
+use serde_json;
+use toml;
+
+/// One rating system to run. The name defaults to the system type, and must
+/// be unique when the same type is run with several parameter sets.
+#[derive(Clone, Debug, Deserialize)]
+pub struct SystemConfig {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub name: Option<String>,
+    #[serde(default)]
+    pub params: Settings,
+}
+
+impl SystemConfig {
+    pub fn name(&self) -> &str {
+        self.name.as_ref().unwrap_or(&self.kind)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ConfigFile {
+    systems: Vec<SystemConfig>,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// Every known system with its default parameters
+pub fn all_configs() -> Vec<SystemConfig> {
+    SYSTEMS.iter().map(config_of).collect()
+}
+
+/// The systems run unless others are selected, with their default parameters
+pub fn default_configs() -> Vec<SystemConfig> {
+    SYSTEMS
+        .iter()
+        .filter(|system| system.default)
+        .map(config_of)
+        .collect()
+}
+
+fn config_of(system: &SystemSpec) -> SystemConfig {
+    SystemConfig {
+        kind: system.name.to_string(),
+        name: None,
+        params: Settings::new(),
+    }
+}
+
+/// Read the systems to run from a JSON (by extension) or TOML file
+pub fn load(path: &Path) -> io::Result<Vec<SystemConfig>> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+
+    parse(&contents, path.extension() == Some(OsStr::new("json")))
+}
+
+fn parse(contents: &str, json: bool) -> io::Result<Vec<SystemConfig>> {
+    let config: ConfigFile = if json {
+        serde_json::from_str(contents).map_err(|e| invalid(e.to_string()))?
+    } else {
+        toml::from_str(contents).map_err(|e| invalid(e.to_string()))?
+    };
+
+    Ok(config.systems)
+}
+
+/// Apply a NAME.KEY=VALUE setting from the command line
+pub fn apply_override(configs: &mut [SystemConfig], setting: &str) -> io::Result<()> {
+    let bad_format = || invalid(format!("Expected NAME.KEY=VALUE, got {}", setting));
+
+    let mut parts = setting.splitn(2, '=');
+    let target = parts.next().ok_or_else(bad_format)?;
+    let value = parts.next().ok_or_else(bad_format)?;
+    let value = value.parse::<f32>().map_err(|_| bad_format())?;
+
+    let mut parts = target.splitn(2, '.');
+    let name = parts.next().ok_or_else(bad_format)?;
+    let key = parts.next().ok_or_else(bad_format)?;
+
+    let config = configs
+        .iter_mut()
+        .find(|config| config.name() == name)
+        .ok_or_else(|| invalid(format!("No rating system named {}", name)))?;
+    config.params.insert(key.to_string(), value);
+
+    Ok(())
+}
+
+pub fn instantiate(configs: &[SystemConfig]) -> io::Result<Vec<SystemInstance>> {
+    let mut names = HashSet::new();
+    let mut systems = vec![];
+
+    for config in configs {
+        if !names.insert(config.name()) {
+            return Err(invalid(format!(
+                "Rating system name {} is used twice",
+                config.name()
+            )));
+        }
+        let spec = ratingsystem::lookup(&config.kind)
+            .ok_or_else(|| invalid(format!("Unknown rating system type {}", config.kind)))?;
+        let system = spec
+            .instantiate(config.name(), &config.params)
+            .map_err(|e| invalid(format!("{}: {}", config.name(), e)))?;
+        systems.push(system);
+    }
+
+    if systems.is_empty() {
+        return Err(invalid("No rating systems selected".to_string()));
+    }
+
+    Ok(systems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_override, default_configs, instantiate, parse, SystemConfig};
+
+    fn config(kind: &str, params: &[(&str, f32)]) -> SystemConfig {
+        SystemConfig {
+            kind: kind.to_string(),
+            name: None,
+            params: params
+                .iter()
+                .map(|(key, value)| (key.to_string(), *value))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn parses_toml_and_json() {
+        let toml = r#"
+            [[systems]]
+            type = "G1"
+
+            [[systems]]
+            type = "L2"
+            name = "L2-week"
+            params = { rating_period_days = 7.0, tau = 0.5 }
+        "#;
+        let configs = parse(toml, false).unwrap();
+        assert_eq!(configs.len(), 2);
+        assert_eq!(configs[0].name(), "G1");
+        assert_eq!(configs[1].name(), "L2-week");
+        assert_eq!(configs[1].params["tau"], 0.5);
+
+        let json = r#"{"systems": [{"type": "E", "params": {"k": 32}}]}"#;
+        let configs = parse(json, true).unwrap();
+        assert_eq!(configs[0].params["k"], 32.0);
+        let systems = instantiate(&configs).unwrap();
+        assert_eq!(systems[0].params["k"], 32.0);
+
+        assert!(parse("[[systems]]\nname = \"x\"", false).is_err());
+    }
+
+    #[test]
+    fn defaults_include_a_baseline() {
+        let systems = instantiate(&default_configs()).unwrap();
+        assert!(systems.iter().any(|system| system.baseline));
+    }
+
+    #[test]
+    fn overrides_set_parameters() {
+        let mut configs = default_configs();
+        apply_override(&mut configs, "L2.tau=0.6").unwrap();
+        let systems = instantiate(&configs).unwrap();
+        let l2 = systems.iter().find(|system| system.name == "L2").unwrap();
+        assert!((l2.params["tau"] - 0.6).abs() < 1e-6);
+        // The others keep their defaults
+        assert_eq!(l2.params["max_rd"], 350.0);
+
+        assert!(apply_override(&mut configs, "L2.tau").is_err());
+        assert!(apply_override(&mut configs, "tau=0.6").is_err());
+        assert!(apply_override(&mut configs, "L2.tau=x").is_err());
+        assert!(apply_override(&mut configs, "X.tau=0.6").is_err());
+    }
+
+    #[test]
+    fn rejects_bad_parameters() {
+        for (kind, params) in [
+            ("G2", vec![("tau", 0.0)]),
+            ("G2", vec![("tau", f32::NAN)]),
+            ("G2", vec![("taus", 0.5)]),
+            ("L2", vec![("rating_period_days", 0.0)]),
+            ("L2", vec![("min_rd", 400.0)]),
+            ("G1", vec![("typical_rd", 400.0)]),
+            ("G2B", vec![("max_rd", -1.0)]),
+            ("LG", vec![("volatility", f32::INFINITY)]),
+            ("TS", vec![("draw_probability", 1.0)]),
+            ("E", vec![("k", -20.0)]),
+            ("EU", vec![("scale", 0.0)]),
+        ]
+        .iter()
+        {
+            assert!(
+                instantiate(&[config(kind, params)]).is_err(),
+                "{} {:?}",
+                kind,
+                params
+            );
+        }
+        assert!(instantiate(&[config("G1", &[("typical_rd", 350.0)])]).is_ok());
+        assert!(instantiate(&[config("X", &[])]).is_err());
+        assert!(instantiate(&[config("E", &[]), config("E", &[])]).is_err());
+        assert!(instantiate(&[]).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+/// Davidson's extension of the Bradley-Terry model to draws. A rating
+/// system's expected score gives the strength ratio x of the players, and
+///
+///   P(win) : P(draw) : P(loss) = x : nu * sqrt(x) : 1
+///
+/// The draw tendency nu depends on the level of the game, as stronger
+/// players draw more: ln nu = a + b * (mean rating - 1500) / 400. Both are
+/// learned from the games by stochastic gradient descent on the log-loss.
+#[derive(Clone, Debug)]
+pub struct DrawModel {
+    pub a: f64,
+    pub b: f64,
+}
+
+impl DrawModel {
+    const LEARNING_RATE: f64 = 0.01;
+
+    fn level(mean_rating: f32) -> f64 {
+        (f64::from(mean_rating) - 1500.0) / 400.0
+    }
+
+    /// Win, draw and loss probabilities for the given expected score
+    pub fn probabilities(&self, expected_score: f32, mean_rating: f32) -> [f64; 3] {
+        let e = f64::from(expected_score).clamp(1e-6, 1.0 - 1e-6);
+        let x = e / (1.0 - e);
+        let nu = (self.a + self.b * DrawModel::level(mean_rating)).exp();
+        let draw = nu * x.sqrt();
+        let total = x + draw + 1.0;
+        [x / total, draw / total, 1.0 / total]
+    }
+
+    /// Draw probability of two equal players at the given rating
+    pub fn draw_rate(&self, rating: f32) -> f64 {
+        self.probabilities(0.5, rating)[1]
+    }
+
+    /// Step towards the observed outcome, 0 for a win, 1 for a draw and 2
+    /// for a loss
+    pub fn learn(&mut self, outcome: usize, expected_score: f32, mean_rating: f32) {
+        let draw = self.probabilities(expected_score, mean_rating)[1];
+        // d ln P(outcome) / d ln nu works out to this for all outcomes
+        let is_draw = if outcome == 1 { 1.0 } else { 0.0 };
+        let gradient = is_draw - draw;
+        self.a += DrawModel::LEARNING_RATE * gradient;
+        self.b += DrawModel::LEARNING_RATE * gradient * DrawModel::level(mean_rating);
+    }
+}
+
+impl Default for DrawModel {
+    // 5% draws between equal 1500 players
+    fn default() -> DrawModel {
+        DrawModel {
+            a: (2.0f64 * 0.05 / 0.95).ln(),
+            b: 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DrawModel;
+
+    // Equal players at the given rating, drawing one game in every `every`
+    // and otherwise winning and losing in turn
+    fn learn(model: &mut DrawModel, rating: f32, every: usize, games: usize) {
+        for i in 0..games {
+            let outcome = match i % every {
+                0 => 1,
+                n if n % 2 == 0 => 0,
+                _ => 2,
+            };
+            model.learn(outcome, 0.5, rating);
+        }
+    }
+
+    #[test]
+    fn probabilities_follow_davidson() {
+        let model = DrawModel::default();
+        let [win, draw, loss] = model.probabilities(0.75, 1500.0);
+        assert!((win + draw + loss - 1.0).abs() < 1e-9);
+        // Odds of a win 3 to 1
+        assert!((win / loss - 3.0).abs() < 1e-6);
+        assert!((draw / loss - model.a.exp() * 3f64.sqrt()).abs() < 1e-6);
+        assert!((model.draw_rate(1500.0) - 0.05).abs() < 1e-9);
+    }
+
+    #[test]
+    fn learns_the_draw_rate() {
+        let mut model = DrawModel::default();
+        learn(&mut model, 1500.0, 5, 50_000);
+        assert!((model.draw_rate(1500.0) - 0.2).abs() < 0.01);
+        assert!(model.b.abs() < 1e-9);
+    }
+
+    #[test]
+    fn learns_more_draws_for_stronger_players() {
+        let mut model = DrawModel::default();
+        for _ in 0..1000 {
+            learn(&mut model, 1100.0, 10, 50);
+            learn(&mut model, 1900.0, 3, 51);
+        }
+        assert!(model.b > 0.0);
+        assert!((model.draw_rate(1100.0) - 0.1).abs() < 0.02);
+        assert!((model.draw_rate(1900.0) - 1.0 / 3.0).abs() < 0.02);
+    }
+}
@@ -0,0 +1,360 @@
+use std::any::Any;
+use std::fmt;
+use std::sync::Arc;
+
+use chrono::{DateTime, TimeZone, Utc};
+use pgn_reader::Color;
+use ratingsystem::{
+    color_bonus, downcast, non_negative, positive, Parameters, RatingSystem, Settings, StateFields,
+};
+
+/// How the K-factor of a player develops over their career
+pub trait KSchedule: Parameters + Clone {
+    /// K for the next game, given the player's current rating, the highest
+    /// rating they have had, their number of games and the age of their
+    /// account in days
+    fn k(&self, rating: f32, peak: f32, games: u32, age_days: f32) -> f32;
+
+    /// Rating points white is stronger by
+    fn white_advantage(&self) -> f32;
+}
+
+/// The same K for every player and game
+#[derive(Clone, Debug, PartialEq)]
+pub struct FixedK {
+    pub k: f32,
+    pub white_advantage: f32,
+}
+
+impl Default for FixedK {
+    fn default() -> FixedK {
+        FixedK {
+            k: 20.0,
+            white_advantage: 0.0,
+        }
+    }
+}
+
+impl Parameters for FixedK {
+    fn set(&mut self, key: &str, value: f32) -> Result<(), String> {
+        match key {
+            "k" => self.k = non_negative(key, value)?,
+            "white_advantage" => self.white_advantage = value,
+            _ => return Err(format!("Unknown Elo parameter {}", key)),
+        }
+        Ok(())
+    }
+
+    fn values(&self) -> Settings {
+        let mut values = Settings::new();
+        values.insert("k".to_string(), self.k);
+        values.insert("white_advantage".to_string(), self.white_advantage);
+        values
+    }
+}
+
+impl KSchedule for FixedK {
+    fn k(&self, _rating: f32, _peak: f32, _games: u32, _age_days: f32) -> f32 {
+        self.k
+    }
+
+    fn white_advantage(&self) -> f32 {
+        self.white_advantage
+    }
+}
+
+/// FIDE rules: a high K for new players and (instead of juniors, which we
+/// can't tell) young accounts below a rating, a lower K for everyone else
+/// and the lowest once a player has ever reached a high rating.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FideK {
+    pub new_k: f32,
+    pub new_games: f32,
+    pub young_days: f32,
+    pub young_rating: f32,
+    pub k: f32,
+    pub high_k: f32,
+    pub high_rating: f32,
+    pub white_advantage: f32,
+}
+
+impl Default for FideK {
+    fn default() -> FideK {
+        FideK {
+            new_k: 40.0,
+            new_games: 30.0,
+            young_days: 365.0,
+            young_rating: 2300.0,
+            k: 20.0,
+            high_k: 10.0,
+            high_rating: 2400.0,
+            white_advantage: 0.0,
+        }
+    }
+}
+
+impl Parameters for FideK {
+    fn set(&mut self, key: &str, value: f32) -> Result<(), String> {
+        match key {
+            "new_k" => self.new_k = non_negative(key, value)?,
+            "new_games" => self.new_games = non_negative(key, value)?,
+            "young_days" => self.young_days = non_negative(key, value)?,
+            "young_rating" => self.young_rating = value,
+            "k" => self.k = non_negative(key, value)?,
+            "high_k" => self.high_k = non_negative(key, value)?,
+            "high_rating" => self.high_rating = value,
+            "white_advantage" => self.white_advantage = value,
+            _ => return Err(format!("Unknown FIDE Elo parameter {}", key)),
+        }
+        Ok(())
+    }
+
+    fn values(&self) -> Settings {
+        let mut values = Settings::new();
+        values.insert("new_k".to_string(), self.new_k);
+        values.insert("new_games".to_string(), self.new_games);
+        values.insert("young_days".to_string(), self.young_days);
+        values.insert("young_rating".to_string(), self.young_rating);
+        values.insert("k".to_string(), self.k);
+        values.insert("high_k".to_string(), self.high_k);
+        values.insert("high_rating".to_string(), self.high_rating);
+        values.insert("white_advantage".to_string(), self.white_advantage);
+        values
+    }
+}
+
+impl KSchedule for FideK {
+    fn k(&self, rating: f32, peak: f32, games: u32, age_days: f32) -> f32 {
+        if (games as f32) < self.new_games
+            || (age_days < self.young_days && rating < self.young_rating)
+        {
+            self.new_k
+        } else if peak >= self.high_rating {
+            self.high_k
+        } else {
+            self.k
+        }
+    }
+
+    fn white_advantage(&self) -> f32 {
+        self.white_advantage
+    }
+}
+
+/// USCF rules: K = scale / (effective games + 1), where the effective
+/// number of games is capped by a rating dependent amount. New players
+/// get a high, provisional K that drops with every game.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UscfK {
+    pub scale: f32,
+    pub max_games: f32,
+    pub white_advantage: f32,
+}
+
+impl Default for UscfK {
+    fn default() -> UscfK {
+        UscfK {
+            scale: 800.0,
+            max_games: 50.0,
+            white_advantage: 0.0,
+        }
+    }
+}
+
+impl Parameters for UscfK {
+    fn set(&mut self, key: &str, value: f32) -> Result<(), String> {
+        match key {
+            "scale" => self.scale = positive(key, value)?,
+            "max_games" => self.max_games = positive(key, value)?,
+            "white_advantage" => self.white_advantage = value,
+            _ => return Err(format!("Unknown USCF Elo parameter {}", key)),
+        }
+        Ok(())
+    }
+
+    fn values(&self) -> Settings {
+        let mut values = Settings::new();
+        values.insert("scale".to_string(), self.scale);
+        values.insert("max_games".to_string(), self.max_games);
+        values.insert("white_advantage".to_string(), self.white_advantage);
+        values
+    }
+}
+
+impl KSchedule for UscfK {
+    fn k(&self, rating: f32, _peak: f32, games: u32, _age_days: f32) -> f32 {
+        let cap = if rating < 2355.0 {
+            self.max_games / (0.662 + 0.00000739 * (2569.0 - rating).powf(2.0)).sqrt()
+        } else {
+            self.max_games
+        };
+        let effective_games = (games as f32).min(cap);
+        self.scale / (effective_games + 1.0)
+    }
+
+    fn white_advantage(&self) -> f32 {
+        self.white_advantage
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct EloRating<K> {
+    pub r: f32,
+    pub peak: f32,
+    pub games: u32,
+    pub first_game: Option<DateTime<Utc>>,
+    params: Arc<K>,
+}
+
+impl<K> fmt::Display for EloRating<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:.1},{}", self.r, self.games)
+    }
+}
+
+impl<K: KSchedule> EloRating<K> {
+    pub fn new(params: Arc<K>) -> EloRating<K> {
+        EloRating {
+            r: 1500.0,
+            peak: 1500.0,
+            games: 0,
+            first_game: None,
+            params,
+        }
+    }
+
+    fn calc_e(r1: f32, r2: f32) -> f32 {
+        1.0 / (1.0 + 10.0f32.powf((r2 - r1) / 400.0))
+    }
+
+    fn age_days(&self, now: &DateTime<Utc>) -> f32 {
+        match self.first_game {
+            Some(first_game) => (*now - first_game).num_seconds() as f32 / (24.0 * 60.0 * 60.0),
+            None => 0.0,
+        }
+    }
+}
+
+impl<K: KSchedule> RatingSystem for EloRating<K> {
+    fn rating(&self) -> f32 {
+        self.r
+    }
+
+    // Elo keeps no uncertainty
+    fn deviation(&self) -> f32 {
+        0.0
+    }
+
+    fn expect(
+        &self,
+        color: Color,
+        _old_time: &DateTime<Utc>,
+        _result_time: &DateTime<Utc>,
+        opponent: &dyn RatingSystem,
+        _opponent_time: &DateTime<Utc>,
+    ) -> f32 {
+        let opponent = downcast::<EloRating<K>>(opponent);
+        let r = self.r + color_bonus(color, self.params.white_advantage());
+        EloRating::<K>::calc_e(r, opponent.r)
+    }
+
+    fn update_with_result(
+        &mut self,
+        color: Color,
+        score: f32,
+        _old_time: &DateTime<Utc>,
+        result_time: &DateTime<Utc>,
+        opponent: &dyn RatingSystem,
+        _opponent_time: &DateTime<Utc>,
+    ) {
+        let opponent = downcast::<EloRating<K>>(opponent);
+        let r = self.r + color_bonus(color, self.params.white_advantage());
+        let e = EloRating::<K>::calc_e(r, opponent.r);
+        let k = self
+            .params
+            .k(self.r, self.peak, self.games, self.age_days(result_time));
+
+        self.r += k * (score - e);
+        self.peak = self.peak.max(self.r);
+        self.games += 1;
+        if self.first_game.is_none() {
+            self.first_game = Some(*result_time);
+        }
+    }
+
+    fn serialize(&self) -> String {
+        let first_game = self.first_game.map_or(0, |time| time.timestamp());
+        format!("{},{},{},{}", self.r, self.peak, self.games, first_game)
+    }
+
+    fn restore(&mut self, state: &str) -> Result<(), String> {
+        let mut fields = StateFields::new(state);
+        self.r = fields.next()?;
+        self.peak = fields.next()?;
+        self.games = fields.next()?;
+        // Set by the first game, even if that was at timestamp 0
+        let first_game = fields.next()?;
+        self.first_game = if self.games > 0 {
+            Some(Utc.timestamp(first_game, 0))
+        } else {
+            None
+        };
+        fields.end()
+    }
+
+    fn box_clone(&self) -> Box<dyn RatingSystem> {
+        Box::new(self.clone())
+    }
+
+    fn copy_from(&mut self, other: &dyn RatingSystem) {
+        self.clone_from(downcast::<EloRating<K>>(other));
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FideK, FixedK, KSchedule, UscfK};
+
+    #[test]
+    fn fixed_k_never_changes() {
+        let k = FixedK::default();
+        assert_eq!(k.k(1500.0, 1500.0, 0, 0.0), 20.0);
+        assert_eq!(k.k(2800.0, 2900.0, 5000, 3000.0), 20.0);
+    }
+
+    #[test]
+    fn fide_k_drops_at_each_boundary() {
+        let k = FideK::default();
+        // New for the first 30 games
+        assert_eq!(k.k(1500.0, 1500.0, 29, 1000.0), 40.0);
+        assert_eq!(k.k(1500.0, 1500.0, 30, 1000.0), 20.0);
+        // Young for a year, while below 2300
+        assert_eq!(k.k(2299.0, 2299.0, 30, 364.0), 40.0);
+        assert_eq!(k.k(2300.0, 2300.0, 30, 364.0), 20.0);
+        assert_eq!(k.k(2299.0, 2299.0, 30, 365.0), 20.0);
+        // 10 once the peak reached 2400, even after dropping below it
+        assert_eq!(k.k(2350.0, 2399.0, 30, 1000.0), 20.0);
+        assert_eq!(k.k(2350.0, 2400.0, 30, 1000.0), 10.0);
+        // New players keep the high K whatever their peak
+        assert_eq!(k.k(2450.0, 2450.0, 29, 1000.0), 40.0);
+    }
+
+    #[test]
+    fn uscf_k_follows_the_effective_games() {
+        let k = UscfK::default();
+        assert_eq!(k.k(1500.0, 1500.0, 0, 0.0), 800.0);
+        // From 2355 up the effective games are capped at 50
+        assert_eq!(k.k(2355.0, 2355.0, 49, 0.0), 16.0);
+        assert_eq!(k.k(2355.0, 2355.0, 60, 0.0), 800.0 / 51.0);
+        // At 1500 the cap is 50 / sqrt(0.662 + 0.00000739 * 1069^2)
+        let cap = 50.0 / (0.662f32 + 0.00000739 * 1069.0 * 1069.0).sqrt();
+        assert!((cap - 16.57).abs() < 0.01);
+        assert_eq!(k.k(1500.0, 1500.0, 16, 0.0), 800.0 / 17.0);
+        assert!((k.k(1500.0, 1500.0, 17, 0.0) - 800.0 / (cap + 1.0)).abs() < 1e-3);
+        assert!((k.k(1500.0, 1500.0, 1000, 0.0) - 800.0 / (cap + 1.0)).abs() < 1e-3);
+    }
+}
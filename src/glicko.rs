@@ -1,15 +1,92 @@
-use std::cmp;
+use std::any::Any;
 use std::f32::consts;
 use std::fmt;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use pgn_reader::Color;
+use ratingsystem::{
+    color_bonus, downcast, positive, Parameters, RatingSystem, Settings, StateFields,
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GlickoParams {
+    pub days_until_unrated: f32,
+    pub initial_rd: f32,
+    pub min_rd: f32,
+    /// RD of a typical active player, which grows back to initial_rd in
+    /// days_until_unrated without games
+    pub typical_rd: f32,
+    /// Rating points white is stronger by
+    pub white_advantage: f32,
+}
+
+impl GlickoParams {
+    // Time for RD=50 to RD=350 in 5 years, with the defaults
+    // 350 = sqrt(50^2 + c^2 * 1825)
+    // 350^2 = 50^2 + c^2 * 1825
+    // 350^2 - 50^2 = c^2 * 1825
+    // 350^2 - 50^2 / 1825 = c^2
+    // c = sqrt((350^2 - 50^2) / 1825)
+    // c = ~8.11
+    fn c_2(&self) -> f32 {
+        ((self.initial_rd * self.initial_rd) - (self.typical_rd * self.typical_rd))
+            / self.days_until_unrated
+    }
+}
+
+impl Default for GlickoParams {
+    fn default() -> GlickoParams {
+        GlickoParams {
+            days_until_unrated: 5.0 * 365.0,
+            initial_rd: 350.0,
+            min_rd: 30.0,
+            typical_rd: 50.0,
+            white_advantage: 0.0,
+        }
+    }
+}
+
+impl Parameters for GlickoParams {
+    fn set(&mut self, key: &str, value: f32) -> Result<(), String> {
+        match key {
+            "days_until_unrated" => self.days_until_unrated = positive(key, value)?,
+            "initial_rd" => self.initial_rd = positive(key, value)?,
+            "min_rd" => self.min_rd = positive(key, value)?,
+            "typical_rd" => self.typical_rd = positive(key, value)?,
+            "white_advantage" => self.white_advantage = value,
+            _ => return Err(format!("Unknown Glicko parameter {}", key)),
+        }
+        Ok(())
+    }
 
-use chrono::{DateTime, TimeZone, Utc};
-use pgn_reader::{Color, Outcome};
-use playerdb::Player;
+    fn values(&self) -> Settings {
+        let mut values = Settings::new();
+        values.insert("days_until_unrated".to_string(), self.days_until_unrated);
+        values.insert("initial_rd".to_string(), self.initial_rd);
+        values.insert("min_rd".to_string(), self.min_rd);
+        values.insert("typical_rd".to_string(), self.typical_rd);
+        values.insert("white_advantage".to_string(), self.white_advantage);
+        values
+    }
+
+    // The RD of unrated players is where the growth of the RD starts from
+    fn check(&self) -> Result<(), String> {
+        if self.typical_rd > self.initial_rd {
+            return Err("typical_rd must not be above initial_rd".to_string());
+        }
+        if self.min_rd > self.initial_rd {
+            return Err("min_rd must not be above initial_rd".to_string());
+        }
+        Ok(())
+    }
+}
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct GlickoRating {
     pub r: f32,
     pub rd: f32,
+    params: Arc<GlickoParams>,
 }
 
 impl fmt::Display for GlickoRating {
@@ -19,22 +96,14 @@ impl fmt::Display for GlickoRating {
 }
 
 impl GlickoRating {
-    // Time for RD=50 to RD=350 in 5 years
-    // 350 = sqrt(50^2 + c^2 * 1825)
-    // 350^2 = 50^2 + c^2 * 1825
-    // 350^2 - 50^2 = c^2 * 1825
-    // 350^2 - 50^2 / 1825 = c^2
-    // c = sqrt((350^2 - 50^2) / 1825)
-    // c = ~8.11
-    const DAYS_UNTIL_UNRATED: f32 = 5.0 * 365.0;
-    const C_2: f32 = ((350.0 * 350.0) - (50.0 * 50.0)) / GlickoRating::DAYS_UNTIL_UNRATED;
     // ln 10 / 400
     const Q: f32 = 0.0057565;
 
-    pub fn new() -> GlickoRating {
+    pub fn new(params: Arc<GlickoParams>) -> GlickoRating {
         GlickoRating {
             r: 1500.0,
-            rd: 350.0,
+            rd: params.initial_rd,
+            params,
         }
     }
 
@@ -56,8 +125,8 @@ impl GlickoRating {
     }
 
     fn calc_new_rd(&self, days: f32) -> f32 {
-        let new_rd = (self.rd.powf(2.0) + (days * GlickoRating::C_2)).sqrt();
-        new_rd.min(350.0)
+        let new_rd = (self.rd.powf(2.0) + (days * self.params.c_2())).sqrt();
+        new_rd.min(self.params.initial_rd)
     }
 
     fn calc_days(old: &DateTime<Utc>, now: &DateTime<Utc>) -> f32 {
@@ -67,36 +136,54 @@ impl GlickoRating {
         let days = seconds / (24.0 * 60.0 * 60.0);
         days as f32
     }
+}
+
+impl RatingSystem for GlickoRating {
+    fn rating(&self) -> f32 {
+        self.r
+    }
+
+    fn deviation(&self) -> f32 {
+        self.rd
+    }
 
-    pub fn expect(
-        self,
+    fn expect(
+        &self,
+        color: Color,
         old_time: &DateTime<Utc>,
         result_time: &DateTime<Utc>,
-        opponent: &Player,
+        opponent: &dyn RatingSystem,
+        opponent_time: &DateTime<Utc>,
     ) -> f32 {
+        let opponent = downcast::<GlickoRating>(opponent);
         let days_me = GlickoRating::calc_days(old_time, result_time);
-        let days_him = GlickoRating::calc_days(&opponent.mtime, result_time);
+        let days_him = GlickoRating::calc_days(opponent_time, result_time);
 
         let pre_rd_me = self.calc_new_rd(days_me);
-        let pre_rd_his = opponent.g1rating.calc_new_rd(days_him);
+        let pre_rd_his = opponent.calc_new_rd(days_him);
 
-        GlickoRating::calc_expect(pre_rd_his, pre_rd_me, opponent.g1rating.r, self.r)
+        let r = self.r + color_bonus(color, self.params.white_advantage);
+        GlickoRating::calc_expect(pre_rd_his, pre_rd_me, opponent.r, r)
     }
 
-    pub fn update_with_result(
+    fn update_with_result(
         &mut self,
+        color: Color,
         score: f32,
         old_time: &DateTime<Utc>,
         result_time: &DateTime<Utc>,
-        opponent: &Player,
+        opponent: &dyn RatingSystem,
+        opponent_time: &DateTime<Utc>,
     ) {
+        let opponent = downcast::<GlickoRating>(opponent);
         let days_me = GlickoRating::calc_days(old_time, result_time);
-        let days_him = GlickoRating::calc_days(&opponent.mtime, result_time);
+        let days_him = GlickoRating::calc_days(opponent_time, result_time);
 
         let pre_rd_me = self.calc_new_rd(days_me);
-        let pre_rd_his = opponent.g1rating.calc_new_rd(days_him);
+        let pre_rd_his = opponent.calc_new_rd(days_him);
 
-        let e = GlickoRating::calc_e(pre_rd_his, opponent.g1rating.r, self.r);
+        let r = self.r + color_bonus(color, self.params.white_advantage);
+        let e = GlickoRating::calc_e(pre_rd_his, opponent.r, r);
         let g = GlickoRating::calc_g(pre_rd_his);
 
         let d_2 = 1.0 / (GlickoRating::Q.powf(2.0) * g.powf(2.0) * e * (1.0 - e));
@@ -108,12 +195,35 @@ impl GlickoRating {
         let new_rd = new_rd_sqr.sqrt();
 
         self.r = new_rating;
-        self.rd = new_rd.max(30.0);
+        self.rd = new_rd.max(self.params.min_rd);
+    }
+
+    fn serialize(&self) -> String {
+        format!("{},{}", self.r, self.rd)
+    }
+
+    fn restore(&mut self, state: &str) -> Result<(), String> {
+        let mut fields = StateFields::new(state);
+        self.r = fields.next()?;
+        self.rd = fields.next()?;
+        fields.end()
+    }
+
+    fn box_clone(&self) -> Box<dyn RatingSystem> {
+        Box::new(self.clone())
+    }
+
+    fn copy_from(&mut self, other: &dyn RatingSystem) {
+        self.clone_from(downcast::<GlickoRating>(other));
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
     }
 }
 
 impl Default for GlickoRating {
     fn default() -> GlickoRating {
-        GlickoRating::new()
+        GlickoRating::new(Arc::new(GlickoParams::default()))
     }
 }
@@ -1,17 +1,59 @@
-use std::cmp;
+use std::any::Any;
 use std::f32::consts;
 use std::fmt;
+use std::sync::Arc;
 
-use chrono::{DateTime, TimeZone, Utc};
-use pgn_reader::{Color, Outcome};
-use playerdb::Player;
+use chrono::{DateTime, Utc};
+use pgn_reader::Color;
+use ratingsystem::{
+    color_bonus, downcast, positive, Parameters, RatingSystem, Settings, StateFields,
+};
 use roots::{find_root_regula_falsi, SimpleConvergency};
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Glicko2Params {
+    pub tau: f32,
+    pub volatility: f32,
+    /// Rating points white is stronger by
+    pub white_advantage: f32,
+}
+
+impl Default for Glicko2Params {
+    fn default() -> Glicko2Params {
+        Glicko2Params {
+            tau: 0.75,
+            volatility: 0.06,
+            white_advantage: 0.0,
+        }
+    }
+}
+
+impl Parameters for Glicko2Params {
+    fn set(&mut self, key: &str, value: f32) -> Result<(), String> {
+        match key {
+            "tau" => self.tau = positive(key, value)?,
+            "volatility" => self.volatility = positive(key, value)?,
+            "white_advantage" => self.white_advantage = value,
+            _ => return Err(format!("Unknown Glicko-2 parameter {}", key)),
+        }
+        Ok(())
+    }
+
+    fn values(&self) -> Settings {
+        let mut values = Settings::new();
+        values.insert("tau".to_string(), self.tau);
+        values.insert("volatility".to_string(), self.volatility);
+        values.insert("white_advantage".to_string(), self.white_advantage);
+        values
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Glicko2Rating {
     pub mu: f32,
     pub phi: f32,
     pub sigma: f32,
+    params: Arc<Glicko2Params>,
 }
 
 impl fmt::Display for Glicko2Rating {
@@ -21,23 +63,22 @@ impl fmt::Display for Glicko2Rating {
 }
 
 impl Glicko2Rating {
-    const TAU: f32 = 0.75;
     const QF: f32 = 173.7178;
-    const VOLATILITY: f32 = 0.06;
 
-    pub fn new() -> Glicko2Rating {
+    pub fn new(params: Arc<Glicko2Params>) -> Glicko2Rating {
         Glicko2Rating {
             mu: 0.0,
             phi: 350.0 / Glicko2Rating::QF,
-            sigma: Glicko2Rating::VOLATILITY,
+            sigma: params.volatility,
+            params,
         }
     }
 
-    pub fn r(self) -> f32 {
+    pub fn r(&self) -> f32 {
         1500.0 + self.mu * Glicko2Rating::QF
     }
 
-    pub fn rd(self) -> f32 {
+    pub fn rd(&self) -> f32 {
         self.phi * Glicko2Rating::QF
     }
 
@@ -57,52 +98,79 @@ impl Glicko2Rating {
         1.0 / nom.sqrt()
     }
 
-    fn calc_days(old: &DateTime<Utc>, now: &DateTime<Utc>) -> f32 {
-        let duration = *now - *old;
-        // days returns an integer
-        let seconds = duration.num_seconds() as f64;
-        let days = seconds / (24.0 * 60.0 * 60.0);
-        days as f32
+    // mu with the advantage of the color
+    fn color_mu(&self, color: Color) -> f32 {
+        self.mu + color_bonus(color, self.params.white_advantage) / Glicko2Rating::QF
+    }
+}
+
+impl RatingSystem for Glicko2Rating {
+    fn rating(&self) -> f32 {
+        self.r()
+    }
+
+    fn deviation(&self) -> f32 {
+        self.rd()
     }
 
-    pub fn expect(self, opponent: &Player) -> f32 {
+    // A single game is a rating period, so time since the last game is ignored
+    fn expect(
+        &self,
+        color: Color,
+        _old_time: &DateTime<Utc>,
+        _result_time: &DateTime<Utc>,
+        opponent: &dyn RatingSystem,
+        _opponent_time: &DateTime<Utc>,
+    ) -> f32 {
+        let opponent = downcast::<Glicko2Rating>(opponent);
         let pre_phi_me = (self.phi.powf(2.0) + self.sigma.powf(2.0)).sqrt();
-        let pre_phi_his =
-            (opponent.g2rating.phi.powf(2.0) + opponent.g2rating.sigma.powf(2.0)).sqrt();
-        Glicko2Rating::calc_expect(pre_phi_his, pre_phi_me, opponent.g2rating.mu, self.mu)
+        let pre_phi_his = (opponent.phi.powf(2.0) + opponent.sigma.powf(2.0)).sqrt();
+        Glicko2Rating::calc_expect(pre_phi_his, pre_phi_me, opponent.mu, self.color_mu(color))
     }
 
-    pub fn update_with_result(&mut self, score: f32, opponent: &Player) {
-        let e = Glicko2Rating::calc_e(opponent.g2rating.phi, opponent.g2rating.mu, self.mu);
-        let g = Glicko2Rating::calc_g(opponent.g2rating.phi);
+    fn update_with_result(
+        &mut self,
+        color: Color,
+        score: f32,
+        _old_time: &DateTime<Utc>,
+        _result_time: &DateTime<Utc>,
+        opponent: &dyn RatingSystem,
+        _opponent_time: &DateTime<Utc>,
+    ) {
+        let opponent = downcast::<Glicko2Rating>(opponent);
+        let e = Glicko2Rating::calc_e(opponent.phi, opponent.mu, self.color_mu(color));
+        let g = Glicko2Rating::calc_g(opponent.phi);
 
         let v = 1.0 / (g.powf(2.0) * e * (1.0 - e));
         let delta = v * g * (score - e);
 
         let orig_phi = self.phi;
+        let tau = self.params.tau;
         let a = (self.sigma.powf(2.0)).ln();
 
         let f = |x: f32| {
             (x.exp() * (delta.powf(2.0) - orig_phi.powf(2.0) - v - x.exp())
                 / (2.0 * (orig_phi.powf(2.0) + v + x.exp()).powf(2.0)))
-                - ((x - a) / Glicko2Rating::TAU.powf(2.0))
+                - ((x - a) / tau.powf(2.0))
         };
 
         let b = if delta.powf(2.0) > orig_phi.powf(2.0) + v {
             (delta.powf(2.0) - orig_phi.powf(2.0) - v).ln()
         } else {
             let mut k = 1.0;
-            while f(a - k * Glicko2Rating::TAU) < 0.0 {
+            while f(a - k * tau) < 0.0 {
                 k += 1.0;
             }
-            a - k * Glicko2Rating::TAU
+            a - k * tau
         };
 
         let mut convergency = SimpleConvergency {
             eps: 0.00001,
             max_iter: 30,
         };
-        let root = find_root_regula_falsi(a, b, &f, &mut convergency).unwrap();
+        // If the search fails to converge, as with a tiny tau, the
+        // volatility stays as it was
+        let root = find_root_regula_falsi(a, b, f, &mut convergency).unwrap_or(a);
 
         let sigma = (root / 2.0).exp();
 
@@ -115,10 +183,65 @@ impl Glicko2Rating {
         self.phi = phi;
         self.sigma = sigma;
     }
+
+    fn serialize(&self) -> String {
+        format!("{},{},{}", self.mu, self.phi, self.sigma)
+    }
+
+    fn restore(&mut self, state: &str) -> Result<(), String> {
+        let mut fields = StateFields::new(state);
+        self.mu = fields.next()?;
+        self.phi = fields.next()?;
+        self.sigma = fields.next()?;
+        fields.end()
+    }
+
+    fn box_clone(&self) -> Box<dyn RatingSystem> {
+        Box::new(self.clone())
+    }
+
+    fn copy_from(&mut self, other: &dyn RatingSystem) {
+        self.clone_from(downcast::<Glicko2Rating>(other));
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
 
 impl Default for Glicko2Rating {
     fn default() -> Glicko2Rating {
-        Glicko2Rating::new()
+        Glicko2Rating::new(Arc::new(Glicko2Params::default()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::{TimeZone, Utc};
+    use pgn_reader::Color;
+
+    use super::{Glicko2Params, Glicko2Rating};
+    use ratingsystem::RatingSystem;
+
+    #[test]
+    fn tiny_tau_keeps_the_volatility() {
+        let params = Arc::new(Glicko2Params {
+            tau: 0.001,
+            ..Glicko2Params::default()
+        });
+        // A loss where the volatility search does not converge
+        let mut player = Glicko2Rating::new(params.clone());
+        player.mu = -0.26039404;
+        player.phi = 0.77095747;
+        player.sigma = 0.060000014;
+        let mut opponent = Glicko2Rating::new(params);
+        opponent.mu = 0.6663566;
+        opponent.phi = 0.64661765;
+        let time = Utc.timestamp(0, 0);
+        player.update_with_result(Color::Black, 0.0, &time, &time, &opponent, &time);
+        assert!(player.rating().is_finite() && player.rating() < 1450.0);
+        assert_eq!(player.sigma, 0.060000014);
     }
 }
@@ -0,0 +1,449 @@
+use std::any::Any;
+use std::f32::consts;
+use std::fmt;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use pgn_reader::Color;
+use ratingsystem::{
+    color_bonus, downcast, positive, Parameters, RatingSystem, Settings, StateFields,
+};
+use roots::{find_root_regula_falsi, SimpleConvergency};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Glicko2BatchParams {
+    pub tau: f32,
+    pub volatility: f32,
+    pub rating_period_days: f32,
+    pub max_rd: f32,
+    /// Rating points white is stronger by
+    pub white_advantage: f32,
+}
+
+impl Default for Glicko2BatchParams {
+    fn default() -> Glicko2BatchParams {
+        Glicko2BatchParams {
+            tau: 0.75,
+            volatility: 0.06,
+            rating_period_days: 1.0,
+            max_rd: 350.0,
+            white_advantage: 0.0,
+        }
+    }
+}
+
+impl Parameters for Glicko2BatchParams {
+    fn set(&mut self, key: &str, value: f32) -> Result<(), String> {
+        match key {
+            "tau" => self.tau = positive(key, value)?,
+            "volatility" => self.volatility = positive(key, value)?,
+            "rating_period_days" => self.rating_period_days = positive(key, value)?,
+            "max_rd" => self.max_rd = positive(key, value)?,
+            "white_advantage" => self.white_advantage = value,
+            _ => return Err(format!("Unknown batched Glicko-2 parameter {}", key)),
+        }
+        Ok(())
+    }
+
+    fn values(&self) -> Settings {
+        let mut values = Settings::new();
+        values.insert("tau".to_string(), self.tau);
+        values.insert("volatility".to_string(), self.volatility);
+        values.insert("rating_period_days".to_string(), self.rating_period_days);
+        values.insert("max_rd".to_string(), self.max_rd);
+        values.insert("white_advantage".to_string(), self.white_advantage);
+        values
+    }
+}
+
+/// A game of the current rating period, against the opponent's rating at
+/// the start of it
+#[derive(Clone, Debug, PartialEq)]
+struct PeriodGame {
+    // Shifted by the advantage of the opponent's color
+    opponent_mu: f32,
+    opponent_phi: f32,
+    score: f32,
+}
+
+/// The player's state at the start of a rating period
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct State {
+    mu: f32,
+    phi: f32,
+    sigma: f32,
+}
+
+/// Glicko-2 as Glickman specifies it: the games of a rating period are
+/// collected and rated at once at its end, against the ratings the
+/// opponents had at its start, and the RD of players without games grows
+/// by one period's volatility. Players are brought up to date lazily, when
+/// they next play or are played against.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Glicko2BatchRating {
+    state: State,
+    // Period the state is at the start of, none before the first game
+    period: Option<i64>,
+    games: Vec<PeriodGame>,
+    // The state with the games of the pending period rated, redone when a
+    // game is added rather than every time it is asked for
+    rated: State,
+    params: Arc<Glicko2BatchParams>,
+}
+
+impl fmt::Display for Glicko2BatchRating {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.settled_state();
+        write!(
+            f,
+            "{:.1},{:.1},{:.4}",
+            Glicko2BatchRating::r(state.mu),
+            state.phi * Glicko2BatchRating::QF,
+            state.sigma
+        )
+    }
+}
+
+impl Glicko2BatchRating {
+    const QF: f32 = 173.7178;
+
+    pub fn new(params: Arc<Glicko2BatchParams>) -> Glicko2BatchRating {
+        let state = State {
+            mu: 0.0,
+            phi: params.max_rd / Glicko2BatchRating::QF,
+            sigma: params.volatility,
+        };
+        Glicko2BatchRating {
+            state,
+            period: None,
+            games: vec![],
+            rated: state,
+            params,
+        }
+    }
+
+    fn r(mu: f32) -> f32 {
+        1500.0 + mu * Glicko2BatchRating::QF
+    }
+
+    fn calc_e(phi: f32, mu: f32, opponent_mu: f32) -> f32 {
+        1.0 / (1.0 + (-Glicko2BatchRating::calc_g(phi) * (mu - opponent_mu)).exp())
+    }
+
+    fn calc_g(phi: f32) -> f32 {
+        let nom = 1.0 + ((3.0 * phi.powf(2.0)) / (consts::PI.powf(2.0)));
+        1.0 / nom.sqrt()
+    }
+
+    fn period_of(&self, time: &DateTime<Utc>) -> i64 {
+        let period_seconds = f64::from(self.params.rating_period_days) * 24.0 * 60.0 * 60.0;
+        (time.timestamp() as f64 / period_seconds).floor() as i64
+    }
+
+    /// Grow the RD by the given number of periods without games
+    fn idle(&self, state: State, periods: i64) -> State {
+        let max_phi = self.params.max_rd / Glicko2BatchRating::QF;
+        let phi = (state.phi.powf(2.0) + periods as f32 * state.sigma.powf(2.0)).sqrt();
+        State {
+            phi: phi.min(max_phi),
+            ..state
+        }
+    }
+
+    /// The Glicko-2 step over all games of the period
+    fn rate_period(&self) -> State {
+        if self.games.is_empty() {
+            return self.state;
+        }
+        let State { mu, phi, sigma } = self.state;
+
+        let mut v_inv = 0.0;
+        let mut outcome = 0.0;
+        for game in self.games.iter() {
+            let g = Glicko2BatchRating::calc_g(game.opponent_phi);
+            let e = Glicko2BatchRating::calc_e(game.opponent_phi, mu, game.opponent_mu);
+            v_inv += g.powf(2.0) * e * (1.0 - e);
+            outcome += g * (game.score - e);
+        }
+        let v = 1.0 / v_inv;
+        let delta = v * outcome;
+
+        let tau = self.params.tau;
+        let a = (sigma.powf(2.0)).ln();
+        let f = |x: f32| {
+            (x.exp() * (delta.powf(2.0) - phi.powf(2.0) - v - x.exp())
+                / (2.0 * (phi.powf(2.0) + v + x.exp()).powf(2.0)))
+                - ((x - a) / tau.powf(2.0))
+        };
+
+        let b = if delta.powf(2.0) > phi.powf(2.0) + v {
+            (delta.powf(2.0) - phi.powf(2.0) - v).ln()
+        } else {
+            let mut k = 1.0;
+            while f(a - k * tau) < 0.0 {
+                k += 1.0;
+            }
+            a - k * tau
+        };
+
+        let mut convergency = SimpleConvergency {
+            eps: 0.00001,
+            max_iter: 30,
+        };
+        // If the search fails to converge, as with a tiny tau, the
+        // volatility stays as it was
+        let root = find_root_regula_falsi(a, b, f, &mut convergency).unwrap_or(a);
+        let new_sigma = (root / 2.0).exp();
+
+        let pre_phi = (phi.powf(2.0) + new_sigma.powf(2.0)).sqrt();
+        let new_phi = 1.0 / ((1.0 / pre_phi.powf(2.0)) + (1.0 / v)).sqrt();
+
+        State {
+            mu: mu + new_phi.powf(2.0) * outcome,
+            phi: new_phi,
+            sigma: new_sigma,
+        }
+    }
+
+    /// State at the start of the given period, closing the pending one
+    fn state_at(&self, period: i64) -> State {
+        let last = match self.period {
+            Some(last) if last < period => last,
+            _ => return self.state,
+        };
+        if self.games.is_empty() {
+            self.idle(self.state, period - last)
+        } else {
+            self.idle(self.rated, period - last - 1)
+        }
+    }
+
+    /// State with the games of the pending period rated
+    fn settled_state(&self) -> State {
+        match self.period {
+            Some(period) => self.state_at(period + 1),
+            None => self.state,
+        }
+    }
+
+    // mu with the advantage of the color
+    fn color_mu(&self, mu: f32, color: Color) -> f32 {
+        mu + color_bonus(color, self.params.white_advantage) / Glicko2BatchRating::QF
+    }
+}
+
+impl RatingSystem for Glicko2BatchRating {
+    // Including the games of the period so far
+    fn rating(&self) -> f32 {
+        Glicko2BatchRating::r(self.settled_state().mu)
+    }
+
+    fn deviation(&self) -> f32 {
+        self.settled_state().phi * Glicko2BatchRating::QF
+    }
+
+    // Ratings stay the same within a period
+    fn expect(
+        &self,
+        color: Color,
+        _old_time: &DateTime<Utc>,
+        result_time: &DateTime<Utc>,
+        opponent: &dyn RatingSystem,
+        _opponent_time: &DateTime<Utc>,
+    ) -> f32 {
+        let opponent = downcast::<Glicko2BatchRating>(opponent);
+        let period = self.period_of(result_time);
+        let me = self.state_at(period);
+        let him = opponent.state_at(period);
+        let phi = (me.phi.powf(2.0) + him.phi.powf(2.0)).sqrt();
+        Glicko2BatchRating::calc_e(phi, self.color_mu(me.mu, color), him.mu)
+    }
+
+    fn update_with_result(
+        &mut self,
+        color: Color,
+        score: f32,
+        _old_time: &DateTime<Utc>,
+        result_time: &DateTime<Utc>,
+        opponent: &dyn RatingSystem,
+        _opponent_time: &DateTime<Utc>,
+    ) {
+        let opponent = downcast::<Glicko2BatchRating>(opponent);
+        // Games slightly out of order count in the pending period
+        let period = self.period.map_or(self.period_of(result_time), |last| {
+            last.max(self.period_of(result_time))
+        });
+        if self.period != Some(period) {
+            // New players start in the period of their first game
+            if self.period.is_some() {
+                self.state = self.state_at(period);
+            }
+            self.period = Some(period);
+            self.games.clear();
+        }
+
+        let him = opponent.state_at(period);
+        self.games.push(PeriodGame {
+            opponent_mu: self.color_mu(him.mu, !color),
+            opponent_phi: him.phi,
+            score,
+        });
+        self.rated = self.rate_period();
+    }
+
+    fn serialize(&self) -> String {
+        let state = self.settled_state();
+        format!("{},{},{}", state.mu, state.phi, state.sigma)
+    }
+
+    // The state at the start of the period and the games since
+    fn snapshot(&self) -> String {
+        let period = self
+            .period
+            .map_or("none".to_string(), |period| period.to_string());
+        let mut out = format!(
+            "{},{},{},{},{}",
+            self.state.mu,
+            self.state.phi,
+            self.state.sigma,
+            period,
+            self.games.len()
+        );
+        for game in self.games.iter() {
+            out += &format!(",{},{},{}", game.opponent_mu, game.opponent_phi, game.score);
+        }
+        out
+    }
+
+    fn restore(&mut self, state: &str) -> Result<(), String> {
+        let mut fields = StateFields::new(state);
+        self.state = State {
+            mu: fields.next()?,
+            phi: fields.next()?,
+            sigma: fields.next()?,
+        };
+        self.period = match fields.next::<String>()?.as_str() {
+            "none" => None,
+            period => Some(
+                period
+                    .parse()
+                    .map_err(|_| format!("Bad rating period {}", period))?,
+            ),
+        };
+        let count: usize = fields.next()?;
+        self.games.clear();
+        for _ in 0..count {
+            self.games.push(PeriodGame {
+                opponent_mu: fields.next()?,
+                opponent_phi: fields.next()?,
+                score: fields.next()?,
+            });
+        }
+        self.rated = self.rate_period();
+        fields.end()
+    }
+
+    fn box_clone(&self) -> Box<dyn RatingSystem> {
+        Box::new(self.clone())
+    }
+
+    fn copy_from(&mut self, other: &dyn RatingSystem) {
+        self.clone_from(downcast::<Glicko2BatchRating>(other));
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::{DateTime, TimeZone, Utc};
+    use pgn_reader::Color;
+
+    use super::{Glicko2BatchParams, Glicko2BatchRating, State};
+    use ratingsystem::RatingSystem;
+
+    const QF: f32 = Glicko2BatchRating::QF;
+
+    fn day(day: i64) -> DateTime<Utc> {
+        Utc.timestamp(day * 24 * 60 * 60, 0)
+    }
+
+    // A player who has played before, at the start of period 0
+    fn player(params: &Arc<Glicko2BatchParams>, rating: f32, rd: f32) -> Glicko2BatchRating {
+        let mut player = Glicko2BatchRating::new(params.clone());
+        player.state = State {
+            mu: (rating - 1500.0) / QF,
+            phi: rd / QF,
+            sigma: 0.06,
+        };
+        player.rated = player.state;
+        player.period = Some(0);
+        player
+    }
+
+    fn play(player: &mut Glicko2BatchRating, opponent: &Glicko2BatchRating, score: f32, at: i64) {
+        player.update_with_result(Color::White, score, &day(0), &day(at), opponent, &day(0));
+    }
+
+    // The example in Glickman's "Example of the Glicko-2 system"
+    #[test]
+    fn rates_glickmans_example() {
+        let params = Arc::new(Glicko2BatchParams {
+            tau: 0.5,
+            ..Glicko2BatchParams::default()
+        });
+        let mut me = player(&params, 1500.0, 200.0);
+        play(&mut me, &player(&params, 1400.0, 30.0), 1.0, 0);
+        play(&mut me, &player(&params, 1550.0, 100.0), 0.0, 0);
+        play(&mut me, &player(&params, 1700.0, 300.0), 0.0, 0);
+
+        assert!((me.rating() - 1464.06).abs() < 0.05, "{}", me.rating());
+        assert!((me.deviation() - 151.52).abs() < 0.05, "{}", me.deviation());
+        assert!((me.settled_state().sigma - 0.05999).abs() < 1e-5);
+    }
+
+    #[test]
+    fn rates_a_period_at_once() {
+        let params = Arc::new(Glicko2BatchParams::default());
+        let opponent = player(&params, 1600.0, 80.0);
+        let mut me = player(&params, 1500.0, 150.0);
+        let expect = |me: &Glicko2BatchRating, at: i64| {
+            me.expect(Color::White, &day(0), &day(at), &opponent, &day(0))
+        };
+
+        let before = expect(&me, 0);
+        play(&mut me, &opponent, 1.0, 0);
+        // The win doesn't count until the period is over
+        assert_eq!(expect(&me, 0), before);
+        assert!(expect(&me, 1) > before);
+
+        // Two wins in one period are rated together, as one batch
+        play(&mut me, &opponent, 1.0, 0);
+        let mut batch = player(&params, 1500.0, 150.0);
+        batch.games = me.games.clone();
+        assert_eq!(me.settled_state(), batch.rate_period());
+        assert!(me.rating() > 1500.0);
+    }
+
+    #[test]
+    fn idle_players_grow_their_rd() {
+        let params = Arc::new(Glicko2BatchParams::default());
+        let opponent = player(&params, 1500.0, 80.0);
+        let mut me = player(&params, 1500.0, 60.0);
+        play(&mut me, &opponent, 0.5, 0);
+        let settled = me.settled_state();
+
+        // Nine periods without games after the one played in
+        let idle = me.state_at(10);
+        let phi = (settled.phi.powf(2.0) + 9.0 * settled.sigma.powf(2.0)).sqrt();
+        assert!((idle.phi - phi).abs() < 1e-6);
+        assert_eq!(idle.mu, settled.mu);
+
+        // Up to the maximum
+        assert!((me.state_at(1_000_000).phi * QF - params.max_rd).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,346 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{self, BufReader, BufWriter};
+use std::path::Path;
+
+use pgn_reader::{Color, Outcome};
+
+use super::{ResultUpdate, TimeControl};
+use playerdb::Player;
+use ratingsystem::SystemInstance;
+
+/// Columns before the ratings and deviations of the systems
+const COLUMNS: &[&str] = &["speed", "player", "time", "opponent", "color", "score"];
+
+/// A name as a CSV field, quoted if it holds a comma or a quote, as in
+/// "Carlsen, Magnus" from OTB games
+fn quote(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+/// The fields of a CSV line, unquoting those written by quote
+fn split_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// Writes the ratings of the players after every game to a CSV file, a row
+/// per player and game. Write errors are reported by flush.
+pub struct HistoryRecorder {
+    out: BufWriter<File>,
+    // Only the games of these players, if given
+    players: Option<HashSet<String>>,
+    error: Option<io::Error>,
+}
+
+impl HistoryRecorder {
+    /// Start a history file. With append, as when resuming from a snapshot,
+    /// the rows go after those of an existing file, which must have been
+    /// written for the same systems.
+    pub fn create(
+        path: &Path,
+        systems: &[SystemInstance],
+        players: Option<HashSet<String>>,
+        append: bool,
+    ) -> io::Result<HistoryRecorder> {
+        let mut header = COLUMNS.join(",");
+        for system in systems {
+            header += &format!(",{} rating,{} RD", system.name, system.name);
+        }
+        let file = if append {
+            OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(true)
+                .open(path)?
+        } else {
+            File::create(path)?
+        };
+        let mut out = BufWriter::new(file);
+        if out.get_ref().metadata()?.len() == 0 {
+            writeln!(out, "{}", header)?;
+        } else {
+            let mut first = String::new();
+            BufReader::new(out.get_ref()).read_line(&mut first)?;
+            if first.trim_end() != header {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "History file was written for other systems",
+                ));
+            }
+        }
+        Ok(HistoryRecorder {
+            out,
+            players,
+            error: None,
+        })
+    }
+
+    /// Record both players of the game, after they were updated
+    pub fn record(&mut self, update: &ResultUpdate, white: &Player, black: &Player) {
+        if self.error.is_some() {
+            return;
+        }
+        let sides = [
+            (Color::White, &*update.white, white, &*update.black),
+            (Color::Black, &*update.black, black, &*update.white),
+        ];
+        for (color, name, player, opponent) in sides.iter() {
+            if let Some(players) = &self.players {
+                if !players.contains(*name) {
+                    continue;
+                }
+            }
+            if let Err(e) = self.write_row(update, *color, name, player, opponent) {
+                self.error = Some(e);
+                return;
+            }
+        }
+    }
+
+    fn write_row(
+        &mut self,
+        update: &ResultUpdate,
+        color: Color,
+        name: &str,
+        player: &Player,
+        opponent: &str,
+    ) -> io::Result<()> {
+        let score = match update.result.unwrap() {
+            Outcome::Draw => "0.5",
+            Outcome::Decisive { winner } if winner == color => "1",
+            Outcome::Decisive { .. } => "0",
+        };
+        let color = match color {
+            Color::White => "white",
+            Color::Black => "black",
+        };
+        write!(
+            self.out,
+            "{},{},{},{},{},{}",
+            update.speed.name(),
+            quote(name),
+            update.datetime.format("%Y-%m-%d %H:%M:%S"),
+            quote(opponent),
+            color,
+            score
+        )?;
+        for rating in player.ratings.iter() {
+            write!(
+                self.out,
+                ",{:.1},{:.1}",
+                rating.rating(),
+                rating.deviation()
+            )?;
+        }
+        writeln!(self.out)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.out.flush()
+    }
+}
+
+/// The header and the rows of one player in a history file, optionally
+/// only for one time control
+fn player_rows(
+    path: &Path,
+    player: &str,
+    speed: Option<TimeControl>,
+) -> io::Result<(Vec<String>, Vec<Vec<String>>)> {
+    let mut lines = BufReader::new(File::open(path)?).lines();
+    let header = match lines.next() {
+        Some(header) => header?,
+        None => return Ok((vec![], vec![])),
+    };
+    let header = header.split(',').map(String::from).collect();
+    let mut rows = vec![];
+    for line in lines {
+        let line = line?;
+        let fields = split_fields(&line);
+        if fields.len() < COLUMNS.len() || fields[1] != player {
+            continue;
+        }
+        if let Some(speed) = &speed {
+            if fields[0] != speed.name() {
+                continue;
+            }
+        }
+        rows.push(fields);
+    }
+    Ok((header, rows))
+}
+
+/// Print the rows of one player in a history file as a table, optionally
+/// only for one time control
+pub fn print_history(path: &Path, player: &str, speed: Option<TimeControl>) -> io::Result<()> {
+    let (header, rows) = player_rows(path, player, speed)?;
+    if header.is_empty() {
+        return Ok(());
+    }
+    let widths: Vec<_> = header.iter().map(|column| column.len().max(7)).collect();
+    let print_row = |fields: &[String]| {
+        let row: Vec<_> = fields
+            .iter()
+            .zip(widths.iter())
+            .enumerate()
+            .map(|(i, (field, width))| match i {
+                // Player and opponent names are left aligned
+                1 | 3 => format!("{:<20}", field),
+                2 => format!("{:19}", field),
+                _ => format!("{:>width$}", field, width = width),
+            })
+            .collect();
+        println!("{}", row.join(" "));
+    };
+
+    print_row(&header);
+    for row in rows.iter() {
+        print_row(row);
+    }
+    println!("{} games of {}", rows.len(), player);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use pgn_reader::{Color, Outcome};
+
+    use super::{player_rows, HistoryRecorder};
+    use playerdb::{Player, RatingDB};
+    use ratingsystem::{lookup, Settings, SystemInstance};
+    use testutil::{game, temp_path};
+    use TimeControl;
+
+    fn systems() -> Vec<SystemInstance> {
+        ["G1", "E"]
+            .iter()
+            .map(|name| {
+                lookup(name)
+                    .unwrap()
+                    .instantiate(name, &Settings::new())
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    fn black_wins() -> Outcome {
+        Outcome::Decisive {
+            winner: Color::Black,
+        }
+    }
+
+    fn record(recorder: &mut HistoryRecorder, white: &str, black: &str, seconds: i64) {
+        let update = game(white, black, black_wins(), seconds);
+        let player = Player::new(&update.datetime, &systems());
+        recorder.record(&update, &player, &player);
+        recorder.flush().unwrap();
+    }
+
+    #[test]
+    fn rows_read_back_and_resume_appends() {
+        let path = temp_path("history.csv");
+        let mut recorder = HistoryRecorder::create(&path, &systems(), None, false).unwrap();
+        record(&mut recorder, "a", "b", 0);
+        drop(recorder);
+
+        let (header, rows) = player_rows(&path, "b", Some(TimeControl::Blitz)).unwrap();
+        assert_eq!(header.len(), 6 + 2 * 2);
+        assert_eq!(header[6], "G1 rating");
+        assert_eq!(
+            rows,
+            vec![vec![
+                "blitz",
+                "b",
+                "1970-01-01 00:00:00",
+                "a",
+                "black",
+                "1",
+                "1500.0",
+                "350.0",
+                "1500.0",
+                "0.0"
+            ]]
+        );
+
+        let mut recorder = HistoryRecorder::create(&path, &systems(), None, true).unwrap();
+        record(&mut recorder, "b", "c", 60);
+        drop(recorder);
+        let (_, rows) = player_rows(&path, "b", None).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1][4], "white");
+        assert!(player_rows(&path, "b", Some(TimeControl::Rapid))
+            .unwrap()
+            .1
+            .is_empty());
+
+        // Resuming with other systems would mix up the columns
+        let other = systems().split_off(1);
+        assert!(HistoryRecorder::create(&path, &other, None, true).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn names_with_commas_and_quotes_read_back() {
+        let path = temp_path("history-quoted.csv");
+        let mut recorder = HistoryRecorder::create(&path, &systems(), None, false).unwrap();
+        record(&mut recorder, "Carlsen, Magnus", "So, Wesley \"W\"", 0);
+        drop(recorder);
+
+        let (_, rows) = player_rows(&path, "Carlsen, Magnus", None).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0][3..6], ["So, Wesley \"W\"", "white", "0"]);
+        assert_eq!(rows[0].len(), 6 + 2 * 2);
+        let (_, rows) = player_rows(&path, "So, Wesley \"W\"", None).unwrap();
+        assert_eq!(rows[0][3], "Carlsen, Magnus");
+        assert!(player_rows(&path, "Carlsen", None).unwrap().1.is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rows_hold_the_ratings_after_the_game() {
+        let path = temp_path("history-update.csv");
+        let mut db = RatingDB::new(systems());
+        let recorder = HistoryRecorder::create(&path, db.systems(), None, false).unwrap();
+        db.record_history(recorder);
+        db.update(&game("a", "b", black_wins(), 0));
+        db.update(&game("b", "c", Outcome::Draw, 60));
+        db.flush_history().unwrap();
+        drop(db);
+        let (_, rows) = player_rows(&path, "b", None).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Glicko from 1500 and RD 350 on both sides: the winner gains 162.2
+        // and its RD drops to 290.2. Elo with K 20 gains 10.
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0][6..], ["1662.2", "290.2", "1510.0", "0.0"]);
+        assert_eq!(rows[1][3..6], ["c", "white", "0.5"]);
+        // A draw against the weaker c costs b a little, and its RD drops on
+        let number = |row: usize, column: usize| rows[row][column].parse::<f64>().unwrap();
+        assert!(number(1, 6) < number(0, 6) && number(1, 7) < number(0, 7));
+    }
+}
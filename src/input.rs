@@ -0,0 +1,414 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::mem;
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Mutex;
+use std::thread;
+
+use bzip2::read::MultiBzDecoder;
+use flate2::read::MultiGzDecoder;
+use indicatif::{ProgressBar, ProgressDrawTarget};
+use xz2::read::XzDecoder;
+use zstd;
+
+use super::{parse_game, GameSink, ParseError, ResultUpdate, TimeControl};
+use cache;
+
+pub struct ProgressBarRead<'a, R> {
+    bar: &'a ProgressBar,
+    read: R,
+}
+
+impl<'a, R: io::Read> ProgressBarRead<'a, R> {
+    pub fn new(bar: &'a ProgressBar, read: R) -> ProgressBarRead<'a, R> {
+        ProgressBarRead { bar, read }
+    }
+}
+
+impl<'a, R: io::Read> io::Read for ProgressBarRead<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let inc = self.read.read(buf)?;
+        self.bar.inc(inc as u64);
+        Ok(inc)
+    }
+}
+
+pub fn progress_bar(input_size: u64) -> ProgressBar {
+    let pb = ProgressBar::new(input_size);
+    pb.set_draw_target(ProgressDrawTarget::stderr());
+    pb.set_style(
+        indicatif::ProgressStyle::default_bar().template(
+            "[{elapsed_precise}] {spinner} {wide_bar} {bytes}/{total_bytes} {msg} [{eta}]",
+        ),
+    );
+    pb
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Cache,
+    Zstd,
+    Bzip2,
+    Gzip,
+    Xz,
+    Plain,
+}
+
+// Enough for the longest magic, the one of xz
+const MAGIC_LEN: u64 = 6;
+
+impl Format {
+    fn detect(magic: &[u8]) -> Format {
+        if magic.starts_with(cache::MAGIC) {
+            Format::Cache
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Format::Zstd
+        } else if magic.starts_with(b"BZh") {
+            Format::Bzip2
+        } else if magic.starts_with(&[0x1f, 0x8b]) {
+            Format::Gzip
+        } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Format::Xz
+        } else {
+            Format::Plain
+        }
+    }
+}
+
+/// The path - stands for stdin
+pub fn is_stdin(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+/// Feed the games from a PGN or a game cache to the sink
+pub fn process_file<S: GameSink>(
+    path: &Path,
+    sink: &mut S,
+    speeds: &[TimeControl],
+    threads: usize,
+) -> io::Result<()> {
+    if !is_stdin(path) {
+        let mut magic = vec![];
+        File::open(path)?.take(MAGIC_LEN).read_to_end(&mut magic)?;
+        if Format::detect(&magic) == Format::Cache {
+            return cache::process_cache(path, sink, speeds);
+        }
+    }
+    process_pgn(path, sink, speeds, threads)
+}
+
+// Number of games handed to a parser thread at once
+const BATCH_SIZE: usize = 1000;
+
+type GameBatch = (u64, Vec<Vec<u8>>);
+// Sequence number, games in the batch, the useful ones among them and
+// the rejected ones
+type UpdateBatch = (u64, usize, Vec<ResultUpdate>, Rejections);
+
+/// Count of the games that could not be rated, by reason, and of the
+/// games of time controls that weren't selected
+#[derive(Default)]
+struct Rejections {
+    counts: BTreeMap<ParseError, u64>,
+    excluded: BTreeMap<TimeControl, u64>,
+}
+
+impl Rejections {
+    fn add(&mut self, error: ParseError) {
+        *self.counts.entry(error).or_insert(0) += 1;
+    }
+
+    fn exclude(&mut self, speed: TimeControl) {
+        *self.excluded.entry(speed).or_insert(0) += 1;
+    }
+
+    fn merge(&mut self, other: &Rejections) {
+        for (error, count) in other.counts.iter() {
+            *self.counts.entry(*error).or_insert(0) += count;
+        }
+        for (speed, count) in other.excluded.iter() {
+            *self.excluded.entry(speed.clone()).or_insert(0) += count;
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.counts.values().sum()
+    }
+}
+
+impl fmt::Display for Rejections {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut lines = vec![];
+        if self.total() > 0 {
+            let mut line = format!("Rejected {} games", self.total());
+            for (i, (error, count)) in self.counts.iter().enumerate() {
+                let separator = if i == 0 { ": " } else { ", " };
+                line += &format!("{}{} {}", separator, count, error);
+            }
+            lines.push(line);
+        }
+        if !self.excluded.is_empty() {
+            let excluded: u64 = self.excluded.values().sum();
+            let mut line = format!("Skipped {} games of other time controls", excluded);
+            for (i, (speed, count)) in self.excluded.iter().enumerate() {
+                let separator = if i == 0 { ": " } else { ", " };
+                line += &format!("{}{} {}", separator, count, speed.name());
+            }
+            lines.push(line);
+        }
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+/// Decompress the input and split it into batches of games, on two blank
+/// lines. Stops early if the parsers have gone away.
+fn split_games<R: Read>(
+    input: R,
+    format: Format,
+    batches: &SyncSender<GameBatch>,
+) -> io::Result<()> {
+    let decoder: Box<dyn Read> = match format {
+        Format::Zstd => Box::new(zstd::Decoder::new(input)?),
+        Format::Bzip2 => Box::new(MultiBzDecoder::new(input)),
+        Format::Gzip => Box::new(MultiGzDecoder::new(input)),
+        Format::Xz => Box::new(XzDecoder::new_multi_decoder(input)),
+        Format::Plain => Box::new(input),
+        Format::Cache => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Game caches can't be read from stdin",
+            ))
+        }
+    };
+
+    // Split on bytes, names need not be UTF-8
+    let mut f = BufReader::new(decoder);
+    let mut line = vec![];
+    let mut pgn_buff = vec![];
+    let mut empty = 0;
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    let mut sequence = 0;
+
+    while f.read_until(b'\n', &mut line)? > 0 {
+        while line.last() == Some(&b'\n') || line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        if line.is_empty() {
+            empty += 1;
+        }
+        if empty == 2 {
+            empty = 0;
+            batch.push(mem::take(&mut pgn_buff));
+            if batch.len() == BATCH_SIZE {
+                let full = mem::replace(&mut batch, Vec::with_capacity(BATCH_SIZE));
+                if batches.send((sequence, full)).is_err() {
+                    return Ok(());
+                }
+                sequence += 1;
+            }
+        } else {
+            pgn_buff.extend_from_slice(&line);
+            pgn_buff.push(b'\n');
+        }
+        line.clear();
+    }
+    if !batch.is_empty() {
+        let _ = batches.send((sequence, batch));
+    }
+
+    Ok(())
+}
+
+/// Parse batches of games until the splitter is done, keeping the games
+/// with one of the given speeds. Games that can't be rated are counted.
+fn parse_games(
+    batches: &Mutex<Receiver<GameBatch>>,
+    updates: &SyncSender<UpdateBatch>,
+    speeds: &[TimeControl],
+) {
+    loop {
+        // Only hold the lock while waiting, not while parsing
+        let next = batches.lock().unwrap().recv();
+        let (sequence, games) = match next {
+            Ok(batch) => batch,
+            Err(_) => return,
+        };
+        let mut useful = vec![];
+        let mut rejections = Rejections::default();
+        for pgn in games.iter() {
+            match parse_game(pgn) {
+                Ok(update) => {
+                    if speeds.contains(&update.speed) {
+                        useful.push(update);
+                    } else {
+                        rejections.exclude(update.speed);
+                    }
+                }
+                Err(error) => rejections.add(error),
+            }
+        }
+        if updates
+            .send((sequence, games.len(), useful, rejections))
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Feed the games from a PGN to the sink. The PGN can be compressed with
+/// zstd, bzip2, gzip or xz, which is detected from its contents.
+///
+/// Decompression and splitting into games runs on one thread, and parsing
+/// the games on the given number of others. The parsed batches are put back
+/// in order before they reach the sink, which only sees the calling thread.
+pub fn process_pgn<S: GameSink>(
+    path: &Path,
+    sink: &mut S,
+    speeds: &[TimeControl],
+    threads: usize,
+) -> io::Result<()> {
+    let (input, input_size): (Box<dyn Read + Send>, u64) = if is_stdin(path) {
+        println!("Processing stdin");
+        (Box::new(io::stdin()), 0)
+    } else {
+        println!("Processing {}", path.display());
+        (Box::new(File::open(path)?), std::fs::metadata(path)?.len())
+    };
+
+    let pb = progress_bar(input_size);
+    let mut pbr = ProgressBarRead::new(&pb, input);
+
+    // stdin can't seek, so put the magic back in front of the stream
+    let mut magic = vec![];
+    pbr.by_ref().take(MAGIC_LEN).read_to_end(&mut magic)?;
+    let format = Format::detect(&magic);
+    let input = io::Cursor::new(magic).chain(pbr);
+
+    let threads = threads.max(1);
+    let (batch_sender, batch_receiver) = sync_channel(2 * threads);
+    let (update_sender, update_receiver) = sync_channel(2 * threads);
+    let batch_receiver = Mutex::new(batch_receiver);
+
+    thread::scope(|scope| {
+        let splitter = scope.spawn(move || split_games(input, format, &batch_sender));
+        for _ in 0..threads {
+            let update_sender = update_sender.clone();
+            let batch_receiver = &batch_receiver;
+            scope.spawn(move || parse_games(batch_receiver, &update_sender, speeds));
+        }
+        drop(update_sender);
+
+        // Batches finish out of order, hold on to them until it's their turn
+        let mut pending = BTreeMap::new();
+        let mut next_sequence = 0;
+        let mut counter: u64 = 0;
+        let mut rejections = Rejections::default();
+        for (sequence, count, updates, rejected) in update_receiver {
+            rejections.merge(&rejected);
+            pending.insert(sequence, (count, updates));
+            while let Some((count, updates)) = pending.remove(&next_sequence) {
+                for update in updates {
+                    sink.add_game(update);
+                }
+                next_sequence += 1;
+
+                let before = counter;
+                counter += count as u64;
+                if counter / 10000 != before / 10000 {
+                    pb.tick();
+                    pb.set_message(&format!("{} games{}", counter, sink.status()));
+                }
+            }
+        }
+
+        splitter.join().unwrap()?;
+        if rejections.total() > 0 || !rejections.excluded.is_empty() {
+            println!("{}", rejections);
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::prelude::*;
+
+    use bzip2::write::BzEncoder;
+    use flate2::write::GzEncoder;
+    use xz2::write::XzEncoder;
+    use zstd;
+
+    use super::{process_pgn, Format, BATCH_SIZE, MAGIC_LEN};
+    use cache;
+    use testutil::temp_path;
+    use {ResultUpdate, TimeControl};
+
+    /// Blitz games of p0, p1 and so on as white
+    fn pgn(games: usize) -> Vec<u8> {
+        let mut pgn = String::new();
+        for i in 0..games {
+            pgn += &format!(
+                "[Event \"Rated Blitz game\"]\n[White \"p{}\"]\n[Black \"q\"]\n[Result \"1-0\"]\n\
+                 [UTCDate \"2018.01.01\"]\n[UTCTime \"00:00:00\"]\n[TimeControl \"180+0\"]\n\n\
+                 1. e4 1-0\n\n",
+                i
+            );
+        }
+        pgn.into_bytes()
+    }
+
+    /// White of every game read from the file
+    fn whites(contents: &[u8], name: &str, threads: usize) -> Vec<String> {
+        let path = temp_path(name);
+        fs::write(&path, contents).unwrap();
+        let mut games: Vec<ResultUpdate> = vec![];
+        let result = process_pgn(&path, &mut games, &[TimeControl::Blitz], threads);
+        fs::remove_file(&path).unwrap();
+        result.unwrap();
+        games.iter().map(|game| game.white.to_string()).collect()
+    }
+
+    #[test]
+    fn games_reach_the_sink_in_order() {
+        let count = 20 * BATCH_SIZE + 7;
+        let expected: Vec<_> = (0..count).map(|i| format!("p{}", i)).collect();
+        assert_eq!(whites(&pgn(count), "order", 4), expected);
+    }
+
+    #[test]
+    fn every_format_is_detected_and_read() {
+        let plain = pgn(3);
+        let mut bzip2 = BzEncoder::new(vec![], bzip2::Compression::default());
+        bzip2.write_all(&plain).unwrap();
+        let mut gzip = GzEncoder::new(vec![], flate2::Compression::default());
+        gzip.write_all(&plain).unwrap();
+        let mut xz = XzEncoder::new(vec![], 6);
+        xz.write_all(&plain).unwrap();
+        let inputs = vec![
+            (Format::Zstd, zstd::encode_all(&plain[..], 0).unwrap()),
+            (Format::Bzip2, bzip2.finish().unwrap()),
+            (Format::Gzip, gzip.finish().unwrap()),
+            (Format::Xz, xz.finish().unwrap()),
+            (Format::Plain, plain.clone()),
+        ];
+        for (format, contents) in inputs {
+            assert_eq!(Format::detect(&contents[..MAGIC_LEN as usize]), format);
+            assert_eq!(
+                whites(&contents, "format", 2),
+                vec!["p0", "p1", "p2"],
+                "{:?}",
+                format
+            );
+        }
+        assert_eq!(Format::detect(cache::MAGIC), Format::Cache);
+        // Shorter than any magic
+        assert_eq!(Format::detect(b"[E"), Format::Plain);
+    }
+}
@@ -0,0 +1,394 @@
+use std::any::Any;
+use std::f64::consts;
+use std::fmt;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use pgn_reader::Color;
+use ratingsystem::{
+    color_bonus, downcast, positive, Parameters, RatingSystem, Settings, StateFields,
+};
+
+// Defaults as lichess ran them in 2018
+#[derive(Clone, Debug, PartialEq)]
+pub struct LichessGlicko2Params {
+    pub tau: f32,
+    pub initial_rd: f32,
+    pub volatility: f32,
+    pub rating_periods_per_day: f32,
+    pub min_rd: f32,
+    pub max_rd: f32,
+    pub max_volatility: f32,
+    pub min_rating: f32,
+    /// Players with a higher RD are provisional
+    pub provisional_rd: f32,
+    /// Rating points white is stronger by, which lichess doesn't have
+    pub white_advantage: f32,
+}
+
+impl Default for LichessGlicko2Params {
+    fn default() -> LichessGlicko2Params {
+        LichessGlicko2Params {
+            tau: 0.75,
+            initial_rd: 350.0,
+            volatility: 0.06,
+            rating_periods_per_day: 0.21436,
+            min_rd: 45.0,
+            max_rd: 350.0,
+            max_volatility: 0.1,
+            min_rating: 600.0,
+            provisional_rd: 110.0,
+            white_advantage: 0.0,
+        }
+    }
+}
+
+impl Parameters for LichessGlicko2Params {
+    fn set(&mut self, key: &str, value: f32) -> Result<(), String> {
+        match key {
+            "tau" => self.tau = positive(key, value)?,
+            "initial_rd" => self.initial_rd = positive(key, value)?,
+            "volatility" => self.volatility = positive(key, value)?,
+            "rating_periods_per_day" => self.rating_periods_per_day = positive(key, value)?,
+            "min_rd" => self.min_rd = positive(key, value)?,
+            "max_rd" => self.max_rd = positive(key, value)?,
+            "max_volatility" => self.max_volatility = positive(key, value)?,
+            "min_rating" => self.min_rating = value,
+            "provisional_rd" => self.provisional_rd = positive(key, value)?,
+            "white_advantage" => self.white_advantage = value,
+            _ => return Err(format!("Unknown lichess Glicko-2 parameter {}", key)),
+        }
+        Ok(())
+    }
+
+    fn values(&self) -> Settings {
+        let mut values = Settings::new();
+        values.insert("tau".to_string(), self.tau);
+        values.insert("initial_rd".to_string(), self.initial_rd);
+        values.insert("volatility".to_string(), self.volatility);
+        values.insert(
+            "rating_periods_per_day".to_string(),
+            self.rating_periods_per_day,
+        );
+        values.insert("min_rd".to_string(), self.min_rd);
+        values.insert("max_rd".to_string(), self.max_rd);
+        values.insert("max_volatility".to_string(), self.max_volatility);
+        values.insert("min_rating".to_string(), self.min_rating);
+        values.insert("provisional_rd".to_string(), self.provisional_rd);
+        values.insert("white_advantage".to_string(), self.white_advantage);
+        values
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.min_rd > self.max_rd {
+            return Err("min_rd must not be above max_rd".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Glicko-2 the way lichess computes it: every game is its own rating
+/// period, but the RD grows with the fractional number of periods since
+/// the player's last game. Kept in double precision on the Glicko scale,
+/// like lichess, so the rounded ratings come out the same.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LichessGlicko2Rating {
+    pub rating: f64,
+    pub rd: f64,
+    pub volatility: f64,
+    pub games: u32,
+    params: Arc<LichessGlicko2Params>,
+}
+
+// The provisional marker gets a column of its own, empty if not
+impl fmt::Display for LichessGlicko2Rating {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let provisional = if self.provisional() { "?" } else { "" };
+        write!(
+            f,
+            "{:.1},{:.1},{:.4},{}",
+            self.rating, self.rd, self.volatility, provisional
+        )
+    }
+}
+
+impl LichessGlicko2Rating {
+    const QF: f64 = 173.7178;
+    const CONVERGENCE_TOLERANCE: f64 = 0.000001;
+
+    pub fn new(params: Arc<LichessGlicko2Params>) -> LichessGlicko2Rating {
+        LichessGlicko2Rating {
+            rating: 1500.0,
+            rd: f64::from(params.initial_rd),
+            volatility: f64::from(params.volatility),
+            games: 0,
+            params,
+        }
+    }
+
+    pub fn provisional(&self) -> bool {
+        self.rd >= f64::from(self.params.provisional_rd)
+    }
+
+    fn mu(&self) -> f64 {
+        (self.rating - 1500.0) / LichessGlicko2Rating::QF
+    }
+
+    fn phi(&self) -> f64 {
+        self.rd / LichessGlicko2Rating::QF
+    }
+
+    // mu with the advantage of the color
+    fn color_mu(&self, color: Color) -> f64 {
+        let bonus = color_bonus(color, self.params.white_advantage);
+        self.mu() + f64::from(bonus) / LichessGlicko2Rating::QF
+    }
+
+    fn calc_g(phi: f64) -> f64 {
+        1.0 / (1.0 + 3.0 * phi.powi(2) / consts::PI.powi(2)).sqrt()
+    }
+
+    fn calc_e(mu: f64, opponent_mu: f64, opponent_phi: f64) -> f64 {
+        1.0 / (1.0 + (-LichessGlicko2Rating::calc_g(opponent_phi) * (mu - opponent_mu)).exp())
+    }
+
+    /// Rating periods since the last game. Players without games count
+    /// one, like in lichess.
+    fn elapsed_periods(&self, old_time: &DateTime<Utc>, result_time: &DateTime<Utc>) -> f64 {
+        if self.games == 0 {
+            return 1.0;
+        }
+        let seconds = (*result_time - *old_time).num_seconds() as f64;
+        seconds / (24.0 * 60.0 * 60.0) * f64::from(self.params.rating_periods_per_day)
+    }
+
+    /// New volatility, by the Illinois algorithm as in lichess
+    fn new_volatility(&self, phi: f64, v: f64, delta: f64) -> f64 {
+        let tau = f64::from(self.params.tau);
+        let a = self.volatility.powi(2).ln();
+        let f = |x: f64| {
+            x.exp() * (delta.powi(2) - phi.powi(2) - v - x.exp())
+                / (2.0 * (phi.powi(2) + v + x.exp()).powi(2))
+                - (x - a) / tau.powi(2)
+        };
+
+        let mut lower = a;
+        let mut upper = if delta.powi(2) > phi.powi(2) + v {
+            (delta.powi(2) - phi.powi(2) - v).ln()
+        } else {
+            let mut k = 1.0;
+            while f(a - k * tau) < 0.0 {
+                k += 1.0;
+            }
+            a - k * tau
+        };
+
+        let mut f_lower = f(lower);
+        let mut f_upper = f(upper);
+        while (upper - lower).abs() > LichessGlicko2Rating::CONVERGENCE_TOLERANCE {
+            let c = lower + (lower - upper) * f_lower / (f_upper - f_lower);
+            let f_c = f(c);
+            if f_c * f_upper < 0.0 {
+                lower = upper;
+                f_lower = f_upper;
+            } else {
+                f_lower /= 2.0;
+            }
+            upper = c;
+            f_upper = f_c;
+        }
+
+        (lower / 2.0).exp()
+    }
+}
+
+impl RatingSystem for LichessGlicko2Rating {
+    fn rating(&self) -> f32 {
+        self.rating as f32
+    }
+
+    fn deviation(&self) -> f32 {
+        self.rd as f32
+    }
+
+    // Without going through f32, which could round up to the next integer
+    fn int_rating(&self) -> i32 {
+        self.rating as i32
+    }
+
+    fn expect(
+        &self,
+        color: Color,
+        _old_time: &DateTime<Utc>,
+        _result_time: &DateTime<Utc>,
+        opponent: &dyn RatingSystem,
+        _opponent_time: &DateTime<Utc>,
+    ) -> f32 {
+        let opponent = downcast::<LichessGlicko2Rating>(opponent);
+        LichessGlicko2Rating::calc_e(self.color_mu(color), opponent.mu(), opponent.phi()) as f32
+    }
+
+    fn update_with_result(
+        &mut self,
+        color: Color,
+        score: f32,
+        old_time: &DateTime<Utc>,
+        result_time: &DateTime<Utc>,
+        opponent: &dyn RatingSystem,
+        _opponent_time: &DateTime<Utc>,
+    ) {
+        let opponent = downcast::<LichessGlicko2Rating>(opponent);
+        let params = &self.params;
+        let mu = self.mu();
+        let phi = self.phi();
+
+        // The opponent's RD is taken as is, without the time since their
+        // last game
+        let g = LichessGlicko2Rating::calc_g(opponent.phi());
+        let e = LichessGlicko2Rating::calc_e(self.color_mu(color), opponent.mu(), opponent.phi());
+        let v = 1.0 / (g.powi(2) * e * (1.0 - e));
+        let outcome = g * (f64::from(score) - e);
+        let delta = v * outcome;
+
+        let volatility = self.new_volatility(phi, v, delta);
+        let phi_star =
+            (phi.powi(2) + self.elapsed_periods(old_time, result_time) * volatility.powi(2)).sqrt();
+        let new_phi = 1.0 / (1.0 / phi_star.powi(2) + 1.0 / v).sqrt();
+        let new_mu = mu + new_phi.powi(2) * outcome;
+
+        self.rating =
+            (1500.0 + new_mu * LichessGlicko2Rating::QF).max(f64::from(params.min_rating));
+        self.rd = (new_phi * LichessGlicko2Rating::QF)
+            .max(f64::from(params.min_rd))
+            .min(f64::from(params.max_rd));
+        self.volatility = volatility.min(f64::from(params.max_volatility));
+        self.games += 1;
+    }
+
+    fn serialize(&self) -> String {
+        format!(
+            "{},{},{},{}",
+            self.rating, self.rd, self.volatility, self.games
+        )
+    }
+
+    fn restore(&mut self, state: &str) -> Result<(), String> {
+        let mut fields = StateFields::new(state);
+        self.rating = fields.next()?;
+        self.rd = fields.next()?;
+        self.volatility = fields.next()?;
+        self.games = fields.next()?;
+        fields.end()
+    }
+
+    fn box_clone(&self) -> Box<dyn RatingSystem> {
+        Box::new(self.clone())
+    }
+
+    fn copy_from(&mut self, other: &dyn RatingSystem) {
+        self.clone_from(downcast::<LichessGlicko2Rating>(other));
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::{TimeZone, Utc};
+    use pgn_reader::Color;
+
+    use super::{LichessGlicko2Params, LichessGlicko2Rating};
+    use ratingsystem::RatingSystem;
+
+    // lila's constants, with its current maximum RD
+    fn params() -> Arc<LichessGlicko2Params> {
+        Arc::new(LichessGlicko2Params {
+            max_rd: 500.0,
+            ..LichessGlicko2Params::default()
+        })
+    }
+
+    fn player(rating: f64, rd: f64, volatility: f64) -> LichessGlicko2Rating {
+        LichessGlicko2Rating {
+            rating,
+            rd,
+            volatility,
+            games: 10,
+            ..LichessGlicko2Rating::new(params())
+        }
+    }
+
+    /// The new rating, RD and volatility and the rating diff lichess would
+    /// show, for a game the given number of days after the player's last one
+    fn rate(
+        me: (f64, f64, f64),
+        opponent: (f64, f64),
+        score: f32,
+        days: i64,
+    ) -> (f64, f64, f64, i32) {
+        let mut me = player(me.0, me.1, me.2);
+        let before = me.int_rating();
+        let opponent = player(opponent.0, opponent.1, 0.06);
+        let last = Utc.timestamp(0, 0);
+        let now = Utc.timestamp(days * 24 * 60 * 60, 0);
+        me.update_with_result(Color::White, score, &last, &now, &opponent, &last);
+        (me.rating, me.rd, me.volatility, me.int_rating() - before)
+    }
+
+    fn assert_close(actual: (f64, f64, f64, i32), expected: (f64, f64, f64, i32)) {
+        assert_eq!(actual.3, expected.3, "{:?}", actual);
+        assert!((actual.0 - expected.0).abs() < 1e-3, "{:?}", actual);
+        assert!((actual.1 - expected.1).abs() < 1e-3, "{:?}", actual);
+        assert!((actual.2 - expected.2).abs() < 1e-6, "{:?}", actual);
+    }
+
+    // Expected values computed by hand from lila's Glicko-2, with 2 days
+    // being 0.42872 rating periods
+    #[test]
+    fn matches_lila_on_glickmans_players() {
+        let me = (1500.0, 200.0, 0.06);
+        assert_close(
+            rate(me, (1400.0, 30.0), 1.0, 2),
+            (1563.4887, 175.2985, 0.0599970, 63),
+        );
+        assert_close(
+            rate(me, (1700.0, 300.0), 0.0, 2),
+            (1455.9172, 186.8571, 0.0599982, -45),
+        );
+    }
+
+    #[test]
+    fn matches_lila_at_its_limits() {
+        // Rating floor
+        assert_close(
+            rate((620.0, 150.0, 0.06), (700.0, 60.0), 0.0, 2),
+            (600.0, 138.7397, 0.0599973, -20),
+        );
+        // Minimum RD
+        assert_close(
+            rate((1500.0, 45.0, 0.06), (1500.0, 45.0), 0.5, 0),
+            (1500.0, 45.0, 0.0599927, 0),
+        );
+        // Maximum volatility
+        assert_close(
+            rate((1500.0, 100.0, 0.12), (1500.0, 60.0), 1.0, 2),
+            (1526.6322, 97.0529, 0.1, 26),
+        );
+    }
+
+    #[test]
+    fn provisional_marker_has_its_own_column() {
+        assert_eq!(
+            player(1500.0, 200.0, 0.06).to_string(),
+            "1500.0,200.0,0.0600,?"
+        );
+        assert_eq!(
+            player(1500.0, 60.0, 0.06).to_string(),
+            "1500.0,60.0,0.0600,"
+        );
+    }
+}
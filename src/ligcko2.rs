@@ -1,17 +1,83 @@
-use std::cmp;
+use std::any::Any;
 use std::f32::consts;
 use std::fmt;
+use std::sync::Arc;
 
-use chrono::{DateTime, TimeZone, Utc};
-use pgn_reader::{Color, Outcome};
-use playerdb::Player;
+use chrono::{DateTime, Utc};
+use pgn_reader::Color;
+use ratingsystem::{
+    color_bonus, downcast, positive, Parameters, RatingSystem, Settings, StateFields,
+};
 use roots::{find_root_regula_falsi, SimpleConvergency};
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ligcko2Params {
+    pub tau: f32,
+    pub volatility: f32,
+    pub rating_period_days: f32,
+    pub max_rd: f32,
+    pub min_rd: f32,
+    pub max_volatility: f32,
+    /// Rating points white is stronger by
+    pub white_advantage: f32,
+}
+
+impl Default for Ligcko2Params {
+    fn default() -> Ligcko2Params {
+        Ligcko2Params {
+            tau: 0.75,
+            volatility: 0.06,
+            // Chosen so a typical player's RD goes from 60 -> 110 in 1 year
+            rating_period_days: 4.665,
+            max_rd: 350.0,
+            min_rd: 60.0,
+            max_volatility: 0.1,
+            white_advantage: 0.0,
+        }
+    }
+}
+
+impl Parameters for Ligcko2Params {
+    fn set(&mut self, key: &str, value: f32) -> Result<(), String> {
+        match key {
+            "tau" => self.tau = positive(key, value)?,
+            "volatility" => self.volatility = positive(key, value)?,
+            "rating_period_days" => self.rating_period_days = positive(key, value)?,
+            "max_rd" => self.max_rd = positive(key, value)?,
+            "min_rd" => self.min_rd = positive(key, value)?,
+            "max_volatility" => self.max_volatility = positive(key, value)?,
+            "white_advantage" => self.white_advantage = value,
+            _ => return Err(format!("Unknown Ligcko-2 parameter {}", key)),
+        }
+        Ok(())
+    }
+
+    fn values(&self) -> Settings {
+        let mut values = Settings::new();
+        values.insert("tau".to_string(), self.tau);
+        values.insert("volatility".to_string(), self.volatility);
+        values.insert("rating_period_days".to_string(), self.rating_period_days);
+        values.insert("max_rd".to_string(), self.max_rd);
+        values.insert("min_rd".to_string(), self.min_rd);
+        values.insert("max_volatility".to_string(), self.max_volatility);
+        values.insert("white_advantage".to_string(), self.white_advantage);
+        values
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.min_rd > self.max_rd {
+            return Err("min_rd must not be above max_rd".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Ligcko2Rating {
     pub mu: f32,
     pub phi: f32,
     pub sigma: f32,
+    params: Arc<Ligcko2Params>,
 }
 
 impl fmt::Display for Ligcko2Rating {
@@ -21,28 +87,22 @@ impl fmt::Display for Ligcko2Rating {
 }
 
 impl Ligcko2Rating {
-    const TAU: f32 = 0.75;
     const QF: f32 = 173.7178;
-    const VOLATILITY: f32 = 0.06;
-    // Chosen so a typical player's RD goes from 60 -> 110 in 1 year
-    const RATING_PERIOD_DAYS: f32 = 4.665;
-    const MAX_PHI: f32 = 350.0 / Ligcko2Rating::QF;
-    const MIN_PHI: f32 = 60.0 / Ligcko2Rating::QF;
-    const MAX_VOLATILITY: f32 = 0.1;
-
-    pub fn new() -> Ligcko2Rating {
+
+    pub fn new(params: Arc<Ligcko2Params>) -> Ligcko2Rating {
         Ligcko2Rating {
             mu: 0.0,
-            phi: 350.0 / Ligcko2Rating::QF,
-            sigma: Ligcko2Rating::VOLATILITY,
+            phi: params.max_rd / Ligcko2Rating::QF,
+            sigma: params.volatility,
+            params,
         }
     }
 
-    pub fn r(self) -> f32 {
+    pub fn r(&self) -> f32 {
         1500.0 + self.mu * Ligcko2Rating::QF
     }
 
-    pub fn rd(self) -> f32 {
+    pub fn rd(&self) -> f32 {
         self.phi * Ligcko2Rating::QF
     }
 
@@ -72,88 +132,136 @@ impl Ligcko2Rating {
 
     fn calc_new_phi(&self, days: f32) -> f32 {
         let new_phi = (self.phi.powf(2.0)
-            + ((days / Ligcko2Rating::RATING_PERIOD_DAYS) * self.sigma.powf(2.0))).sqrt();
-        new_phi.min(Ligcko2Rating::MAX_PHI)
+            + ((days / self.params.rating_period_days) * self.sigma.powf(2.0))).sqrt();
+        new_phi.min(self.params.max_rd / Ligcko2Rating::QF)
     }
 
-    pub fn expect(
-        self,
+    // mu with the advantage of the color
+    fn color_mu(&self, color: Color) -> f32 {
+        self.mu + color_bonus(color, self.params.white_advantage) / Ligcko2Rating::QF
+    }
+}
+
+impl RatingSystem for Ligcko2Rating {
+    fn rating(&self) -> f32 {
+        self.r()
+    }
+
+    fn deviation(&self) -> f32 {
+        self.rd()
+    }
+
+    fn expect(
+        &self,
+        color: Color,
         old_time: &DateTime<Utc>,
         result_time: &DateTime<Utc>,
-        opponent: &Player,
+        opponent: &dyn RatingSystem,
+        opponent_time: &DateTime<Utc>,
     ) -> f32 {
+        let opponent = downcast::<Ligcko2Rating>(opponent);
         let days_me = Ligcko2Rating::calc_days(old_time, result_time);
-        let days_him = Ligcko2Rating::calc_days(&opponent.mtime, result_time);
+        let days_him = Ligcko2Rating::calc_days(opponent_time, result_time);
 
         let pre_phi_me = self.calc_new_phi(days_me);
-        let pre_phi_his = opponent.l2rating.calc_new_phi(days_him);
+        let pre_phi_his = opponent.calc_new_phi(days_him);
 
-        Ligcko2Rating::calc_expect(pre_phi_his, pre_phi_me, opponent.l2rating.mu, self.mu)
+        Ligcko2Rating::calc_expect(pre_phi_his, pre_phi_me, opponent.mu, self.color_mu(color))
     }
 
-    pub fn update_with_result(
+    fn update_with_result(
         &mut self,
+        color: Color,
         score: f32,
         old_time: &DateTime<Utc>,
         result_time: &DateTime<Utc>,
-        opponent: &Player,
+        opponent: &dyn RatingSystem,
+        _opponent_time: &DateTime<Utc>,
     ) {
+        let opponent = downcast::<Ligcko2Rating>(opponent);
         let days_me = Ligcko2Rating::calc_days(old_time, result_time);
-        let days_him = Ligcko2Rating::calc_days(&opponent.mtime, result_time);
 
         // Not used in Glicko-2?
         // This means we overstate the reliability of the opponent's rating
-        // let pre_phi_his = opponent.l2rating.calc_new_phi(days_him);
+        // let days_him = Ligcko2Rating::calc_days(opponent_time, result_time);
+        // let pre_phi_his = opponent.calc_new_phi(days_him);
         // This is calculated with the new sigma
         // let pre_phi_me = self.calc_new_phi(days_me);
 
-        let e = Ligcko2Rating::calc_e(opponent.l2rating.phi, opponent.l2rating.mu, self.mu);
-        let g = Ligcko2Rating::calc_g(opponent.l2rating.phi);
+        let e = Ligcko2Rating::calc_e(opponent.phi, opponent.mu, self.color_mu(color));
+        let g = Ligcko2Rating::calc_g(opponent.phi);
 
         let v = 1.0 / (g.powf(2.0) * e * (1.0 - e));
         let delta = v * g * (score - e);
 
         let orig_phi = self.phi;
+        let tau = self.params.tau;
         let a = (self.sigma.powf(2.0)).ln();
 
         let f = |x: f32| {
             (x.exp() * (delta.powf(2.0) - orig_phi.powf(2.0) - v - x.exp())
                 / (2.0 * (orig_phi.powf(2.0) + v + x.exp()).powf(2.0)))
-                - ((x - a) / Ligcko2Rating::TAU.powf(2.0))
+                - ((x - a) / tau.powf(2.0))
         };
 
         let b = if delta.powf(2.0) > orig_phi.powf(2.0) + v {
             (delta.powf(2.0) - orig_phi.powf(2.0) - v).ln()
         } else {
             let mut k = 1.0;
-            while f(a - k * Ligcko2Rating::TAU) < 0.0 {
+            while f(a - k * tau) < 0.0 {
                 k += 1.0;
             }
-            a - k * Ligcko2Rating::TAU
+            a - k * tau
         };
 
         let mut convergency = SimpleConvergency {
             eps: 0.00001,
             max_iter: 30,
         };
-        let root = find_root_regula_falsi(a, b, &f, &mut convergency).unwrap();
+        // If the search fails to converge, as with a tiny tau, the
+        // volatility stays as it was
+        let root = find_root_regula_falsi(a, b, f, &mut convergency).unwrap_or(a);
 
         let sigma = (root / 2.0).exp();
 
         let phi_star = (orig_phi.powf(2.0)
-            + ((days_me / Ligcko2Rating::RATING_PERIOD_DAYS) * sigma.powf(2.0))).sqrt();
+            + ((days_me / self.params.rating_period_days) * sigma.powf(2.0))).sqrt();
         let phi = 1.0 / ((1.0 / phi_star.powf(2.0)) + (1.0 / v)).sqrt();
 
         let mu = self.mu + phi.powf(2.0) * g * (score - e);
 
         self.mu = mu;
-        self.phi = phi.max(Ligcko2Rating::MIN_PHI);
-        self.sigma = sigma.min(Ligcko2Rating::MAX_VOLATILITY);
+        self.phi = phi.max(self.params.min_rd / Ligcko2Rating::QF);
+        self.sigma = sigma.min(self.params.max_volatility);
+    }
+
+    fn serialize(&self) -> String {
+        format!("{},{},{}", self.mu, self.phi, self.sigma)
+    }
+
+    fn restore(&mut self, state: &str) -> Result<(), String> {
+        let mut fields = StateFields::new(state);
+        self.mu = fields.next()?;
+        self.phi = fields.next()?;
+        self.sigma = fields.next()?;
+        fields.end()
+    }
+
+    fn box_clone(&self) -> Box<dyn RatingSystem> {
+        Box::new(self.clone())
+    }
+
+    fn copy_from(&mut self, other: &dyn RatingSystem) {
+        self.clone_from(downcast::<Ligcko2Rating>(other));
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
     }
 }
 
 impl Default for Ligcko2Rating {
     fn default() -> Ligcko2Rating {
-        Ligcko2Rating::new()
+        Ligcko2Rating::new(Arc::new(Ligcko2Params::default()))
     }
 }
@@ -1,31 +1,71 @@
+extern crate bzip2;
 extern crate chrono;
+extern crate clap;
+extern crate flate2;
 extern crate glob;
 extern crate indicatif;
 extern crate pgn_reader;
 extern crate roots;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate toml;
+extern crate xz2;
 extern crate zstd;
 
+mod advantage;
+mod cache;
+mod config;
+mod drawmodel;
+mod elo;
 mod glicko;
 mod glicko2;
+mod glicko2batch;
+mod history;
+mod input;
+mod lichess;
 mod ligcko2;
+mod optimizer;
 mod playerdb;
+mod ratingsystem;
+mod significance;
+mod strata;
+#[cfg(test)]
+mod testutil;
+mod trueskill;
+mod verify;
+mod whr;
 
+use std::collections::HashSet;
+use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
 use std::str;
-use std::sync::Mutex;
+use std::sync::Arc;
+use std::thread;
 
+use cache::CacheWriter;
 use chrono::{DateTime, TimeZone, Utc};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use config::SystemConfig;
 use glob::glob;
-use indicatif::{ProgressBar, ProgressDrawTarget};
+use history::HistoryRecorder;
+use optimizer::{Metric, Optimization};
 use pgn_reader::Outcome::{self, Decisive, Draw};
 use pgn_reader::{Color, Reader, Skip, Visitor};
-use playerdb::{RatingDB, StatsDB};
+use playerdb::{Perspective, RatingDB, UpdateOrder};
+use ratingsystem::SystemInstance;
+use verify::Verifier;
+use whr::{Whr, WhrParams};
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 enum TimeControl {
     Garbage,
+    // Never rated, like on lichess
+    UltraBullet,
     Bullet,
     Blitz,
     Rapid,
@@ -33,42 +73,197 @@ enum TimeControl {
     Correspondence,
 }
 
+impl TimeControl {
+    const NAMES: &'static [&'static str] =
+        &["bullet", "blitz", "rapid", "classical", "correspondence"];
+
+    fn from_name(name: &str) -> Option<TimeControl> {
+        match name {
+            "bullet" => Some(TimeControl::Bullet),
+            "blitz" => Some(TimeControl::Blitz),
+            "rapid" => Some(TimeControl::Rapid),
+            "classical" => Some(TimeControl::Classical),
+            "correspondence" => Some(TimeControl::Correspondence),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            TimeControl::Garbage => "garbage",
+            TimeControl::UltraBullet => "ultrabullet",
+            TimeControl::Bullet => "bullet",
+            TimeControl::Blitz => "blitz",
+            TimeControl::Rapid => "rapid",
+            TimeControl::Classical => "classical",
+            TimeControl::Correspondence => "correspondence",
+        }
+    }
+}
+
+/// Clock of a game from its TimeControl header, in seconds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Clock {
+    base: u32,
+    increment: u32,
+}
+
+impl Clock {
+    /// Parse a header like 180+2 or 180. An unknown clock (?) or none, as
+    /// in correspondence games (-), gives None, so the event decides the
+    /// speed. Of several periods, like FIDE's 40/7200:3600, the first one
+    /// counts.
+    fn parse(value: &str) -> Result<Option<Clock>, ParseError> {
+        if value == "?" || value == "-" {
+            return Ok(None);
+        }
+        let mut periods = value.split(':').map(Clock::parse_period);
+        let first = periods.next().unwrap();
+        if periods.any(|period| period.is_none()) {
+            return Err(ParseError::BadClock);
+        }
+        first.map(Some).ok_or(ParseError::BadClock)
+    }
+
+    /// One period of a TimeControl header. A period of a number of moves
+    /// (40/7200) or a sandclock (*180) counts like its seconds alone.
+    fn parse_period(period: &str) -> Option<Clock> {
+        let period = match period.find('/') {
+            Some(slash) => {
+                period[..slash].parse::<u32>().ok()?;
+                &period[slash + 1..]
+            }
+            None => period.strip_prefix('*').unwrap_or(period),
+        };
+        let mut parts = period.splitn(2, '+');
+        let base = parts.next()?.parse::<u32>().ok()?;
+        let increment = match parts.next() {
+            Some(increment) => increment.parse::<u32>().ok()?,
+            None => 0,
+        };
+        Some(Clock { base, increment })
+    }
+
+    /// lichess' estimate of the game duration, used to classify the speed.
+    /// Wide enough for any header.
+    fn estimated_duration(&self) -> u64 {
+        u64::from(self.base) + 40 * u64::from(self.increment)
+    }
+
+    fn speed(&self) -> TimeControl {
+        match self.estimated_duration() {
+            0..=29 => TimeControl::UltraBullet,
+            30..=179 => TimeControl::Bullet,
+            180..=479 => TimeControl::Blitz,
+            480..=1499 => TimeControl::Rapid,
+            _ => TimeControl::Classical,
+        }
+    }
+}
+
+/// Why a game was rejected
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ParseError {
+    Empty,
+    NotUtf8,
+    BadDate,
+    Unrated,
+    NotRated,
+    BadClock,
+    UnknownSpeed,
+    NoResult,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            ParseError::Empty => "no headers",
+            ParseError::NotUtf8 => "header not UTF-8",
+            ParseError::BadDate => "bad UTC date or time",
+            ParseError::Unrated => "unrated event",
+            ParseError::NotRated => "not a rated event",
+            ParseError::BadClock => "bad TimeControl",
+            ParseError::UnknownSpeed => "unknown time control",
+            ParseError::NoResult => "no result",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct ResultUpdate {
-    white: String,
-    black: String,
+    white: Arc<str>,
+    black: Arc<str>,
+    // Ids of the players in the name table of the cache the game was read
+    // from, so sinks can skip looking up their names
+    cache_ids: Option<(u32, u32)>,
     result: Option<Outcome>,
     date: String,
     time: String,
     datetime: DateTime<Utc>,
     rated: bool,
     speed: TimeControl,
-    white_rating: i32,
-    black_rating: i32,
+    // lichess ratings of the players, if the headers have valid ones
+    white_rating: Option<i32>,
+    black_rating: Option<i32>,
+    // Rating changes lichess applied for the game
+    white_rating_diff: Option<i32>,
+    black_rating_diff: Option<i32>,
+    clock: Option<Clock>,
+    // First problem found in the headers
+    error: Option<ParseError>,
 }
 
 impl ResultUpdate {
     fn new() -> ResultUpdate {
         ResultUpdate {
-            white: String::default(),
-            black: String::default(),
+            white: Arc::from(""),
+            black: Arc::from(""),
+            cache_ids: None,
             result: None,
             date: String::default(),
             time: String::default(),
             datetime: Utc.timestamp(0, 0),
             rated: false,
             speed: TimeControl::Garbage,
-            white_rating: 1500,
-            black_rating: 1500,
+            white_rating: None,
+            black_rating: None,
+            white_rating_diff: None,
+            black_rating_diff: None,
+            clock: None,
+            error: None,
+        }
+    }
+
+    /// Whether the game can be rated at all
+    fn check(&self) -> Result<(), ParseError> {
+        if let Some(error) = self.error {
+            Err(error)
+        } else if !self.rated {
+            Err(ParseError::NotRated)
+        } else if self.speed == TimeControl::Garbage {
+            Err(ParseError::UnknownSpeed)
+        } else if self.result.is_none() {
+            Err(ParseError::NoResult)
+        } else {
+            Ok(())
         }
     }
 
-    fn valid(&self) -> bool {
-        self.rated && self.speed != TimeControl::Garbage && self.result.is_some()
+    fn reject(&mut self, error: ParseError) {
+        if self.error.is_none() {
+            self.error = Some(error);
+        }
     }
 
-    fn useful(&self) -> bool {
-        self.valid() && self.speed == TimeControl::Blitz
+    fn text(&mut self, value: &[u8]) -> Option<String> {
+        match str::from_utf8(value) {
+            Ok(text) => Some(text.to_string()),
+            Err(_) => {
+                self.reject(ParseError::NotUtf8);
+                None
+            }
+        }
     }
 }
 
@@ -76,11 +271,14 @@ impl<'pgn> Visitor<'pgn> for ResultUpdate {
     type Result = ResultUpdate;
 
     fn header(&mut self, key: &'pgn [u8], value: &'pgn [u8]) {
-        let mut strvalue = str::from_utf8(value).unwrap().to_string();
         if key == b"White" {
-            self.white = strvalue;
+            if let Some(name) = self.text(value) {
+                self.white = name.into();
+            }
         } else if key == b"Black" {
-            self.black = strvalue;
+            if let Some(name) = self.text(value) {
+                self.black = name.into();
+            }
         } else if key == b"Result" {
             if value == b"1-0" {
                 self.result = Some(Decisive {
@@ -94,18 +292,26 @@ impl<'pgn> Visitor<'pgn> for ResultUpdate {
                 self.result = Some(Draw)
             }
         } else if key == b"UTCDate" {
-            self.date = strvalue;
+            if let Some(date) = self.text(value) {
+                self.date = date;
+            }
         } else if key == b"UTCTime" {
-            self.time = strvalue;
+            if let Some(time) = self.text(value) {
+                self.time = time;
+            }
         } else if key == b"Event" {
+            let mut strvalue = match self.text(value) {
+                Some(event) => event,
+                None => return,
+            };
             strvalue.make_ascii_lowercase();
             if strvalue.contains("unrated") {
                 self.rated = false;
-                panic!("lichess DB has no unrated games");
+                self.reject(ParseError::Unrated);
             } else {
-                assert!(strvalue.contains("rated"));
-                self.rated = true;
+                self.rated = strvalue.contains("rated");
             }
+            // Fallback for games without a TimeControl header
             if strvalue.contains("blitz") {
                 self.speed = TimeControl::Blitz;
             } else if strvalue.contains("rapid") {
@@ -116,30 +322,47 @@ impl<'pgn> Visitor<'pgn> for ResultUpdate {
                 // WTF is this
                 self.speed = TimeControl::Classical;
             } else if strvalue.contains("ultrabullet") {
-                self.speed = TimeControl::Garbage;
+                self.speed = TimeControl::UltraBullet;
             } else if strvalue.contains("bullet") {
                 self.speed = TimeControl::Bullet;
-                assert!(!strvalue.contains("ultrabullet"));
             } else if strvalue.contains("correspondence") {
                 self.speed = TimeControl::Correspondence;
             } else {
-                assert!(self.speed == TimeControl::Garbage);
-                // println!("{:?}", strvalue);
+                self.speed = TimeControl::Garbage;
+            }
+        } else if key == b"TimeControl" {
+            let clock = match self.text(value) {
+                Some(clock) => Clock::parse(&clock),
+                None => return,
+            };
+            match clock {
+                Ok(clock) => self.clock = clock,
+                Err(error) => self.reject(error),
             }
         } else if key == b"WhiteElo" {
-            self.white_rating = strvalue.parse::<i32>().unwrap();
+            // Only needed for some stats, so a bad one like ? doesn't
+            // reject the game
+            self.white_rating = str::from_utf8(value).ok().and_then(|v| v.parse().ok());
         } else if key == b"BlackElo" {
-            self.black_rating = strvalue.parse::<i32>().unwrap();
+            self.black_rating = str::from_utf8(value).ok().and_then(|v| v.parse().ok());
+        } else if key == b"WhiteRatingDiff" {
+            self.white_rating_diff = str::from_utf8(value).ok().and_then(|v| v.parse().ok());
+        } else if key == b"BlackRatingDiff" {
+            self.black_rating_diff = str::from_utf8(value).ok().and_then(|v| v.parse().ok());
         }
     }
 
     fn end_headers(&mut self) -> Skip {
         let mut datestring = self.date.clone();
-        datestring.push_str(" ");
+        datestring.push(' ');
         datestring.push_str(&self.time);
-        self.datetime = Utc
-            .datetime_from_str(&datestring, "%Y.%m.%d %H:%M:%S")
-            .unwrap();
+        match Utc.datetime_from_str(&datestring, "%Y.%m.%d %H:%M:%S") {
+            Ok(datetime) => self.datetime = datetime,
+            Err(_) => self.reject(ParseError::BadDate),
+        }
+        if let Some(clock) = self.clock {
+            self.speed = clock.speed();
+        }
         Skip(true)
     }
 
@@ -148,107 +371,829 @@ impl<'pgn> Visitor<'pgn> for ResultUpdate {
     }
 }
 
-fn process_game(pgn: &str, db: &mut RatingDB) {
+/// Consumer of the games read from the input files
+pub trait GameSink {
+    fn add_game(&mut self, update: ResultUpdate);
+
+    /// The games from now on come from a new cache, whose player ids
+    /// have nothing to do with the ones of the previous cache
+    fn start_cache(&mut self) {}
+
+    /// Shown after the game count in the progress bar
+    fn status(&self) -> String;
+}
+
+impl GameSink for RatingDB {
+    fn add_game(&mut self, update: ResultUpdate) {
+        self.update(&update);
+    }
+
+    fn start_cache(&mut self) {
+        self.forget_cache_ids();
+    }
+
+    fn status(&self) -> String {
+        format!(", {} players", self.player_count())
+    }
+}
+
+impl GameSink for Vec<ResultUpdate> {
+    fn add_game(&mut self, update: ResultUpdate) {
+        self.push(update);
+    }
+
+    fn status(&self) -> String {
+        String::new()
+    }
+}
+
+/// Parse the headers of a game, which must be fit for rating
+fn parse_game(pgn: &[u8]) -> Result<ResultUpdate, ParseError> {
     let mut visitor = ResultUpdate::new();
-    let mut reader = Reader::new(&mut visitor, pgn.as_bytes());
+    let mut reader = Reader::new(&mut visitor, pgn);
 
-    let update = reader.read_game();
-    if let Some(update) = update {
-        if update.useful() {
-            db.update(update);
+    let update = reader.read_game().ok_or(ParseError::Empty)?;
+    update.check()?;
+    Ok(update)
+}
+
+/// Write every valid game of each input to a game cache in the output
+/// directory, named after the input.
+fn import(args: &ArgMatches) -> io::Result<()> {
+    let output_dir = Path::new(args.value_of("output").unwrap());
+    let threads = selected_threads(args)?;
+    let all_speeds: Vec<_> = TimeControl::NAMES
+        .iter()
+        .map(|name| TimeControl::from_name(name).unwrap())
+        .collect();
+
+    for path in input_paths(args)? {
+        let file_name = if input::is_stdin(&path) {
+            "stdin".into()
+        } else {
+            path.file_name().unwrap().to_string_lossy()
+        };
+        let stem = file_name
+            .trim_end_matches(".zst")
+            .trim_end_matches(".bz2")
+            .trim_end_matches(".gz")
+            .trim_end_matches(".xz")
+            .trim_end_matches(".pgn");
+        let cache_path = output_dir.join(format!("{}.rrc", stem));
+
+        let mut writer = CacheWriter::create(&cache_path)?;
+        input::process_pgn(&path, &mut writer, &all_speeds, threads)?;
+        if writer.skipped() > 0 {
+            println!("Left out {} games the cache can't hold", writer.skipped());
+        }
+        writer.finish()?;
+        println!("Wrote {}", cache_path.display());
+    }
+
+    Ok(())
+}
+
+fn input_paths(args: &ArgMatches) -> io::Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+    for pattern in args.values_of("INPUT").unwrap() {
+        if input::is_stdin(Path::new(pattern)) {
+            paths.push(PathBuf::from(pattern));
+            continue;
+        }
+        let matches = glob(pattern)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        let mut found = false;
+        for path in matches.filter_map(Result::ok) {
+            paths.push(path);
+            found = true;
+        }
+        if !found {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No input files match {}", pattern),
+            ));
         }
     }
-    //println!("{:?}", update);
+    // The lichess file names sort in date order
+    paths.sort();
+    Ok(paths)
 }
 
-pub struct ProgressBarRead<'a, R> {
-    bar: &'a ProgressBar,
-    read: R,
+fn selected_speeds(args: &ArgMatches) -> Vec<TimeControl> {
+    args.values_of("speeds")
+        .unwrap()
+        .map(|name| TimeControl::from_name(name).unwrap())
+        .collect()
 }
 
-impl<'a, R: io::Read> ProgressBarRead<'a, R> {
-    fn new(bar: &'a ProgressBar, read: R) -> ProgressBarRead<'a, R> {
-        ProgressBarRead { bar, read }
+/// Number of PGN parser threads, by default one per core
+fn selected_threads(args: &ArgMatches) -> io::Result<usize> {
+    match args.value_of("threads") {
+        Some(threads) => threads
+            .parse::<usize>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string())),
+        None => Ok(thread::available_parallelism().map_or(1, |n| n.get())),
     }
 }
 
-impl<'a, R: io::Read> io::Read for ProgressBarRead<'a, R> {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let inc = self.read.read(buf)?;
-        self.bar.inc(inc as u64);
-        Ok(inc)
+/// Number of bootstrap resamples, if significance tests are asked for
+fn selected_resamples(args: &ArgMatches) -> io::Result<Option<usize>> {
+    match args.value_of("bootstrap") {
+        Some(resamples) => match resamples.parse::<usize>() {
+            Ok(0) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--bootstrap needs at least one resample",
+            )),
+            Ok(resamples) => Ok(Some(resamples)),
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidInput, e.to_string())),
+        },
+        None => Ok(None),
     }
 }
 
-fn process_zstd_pgn(path: std::path::PathBuf, db: &mut RatingDB) -> io::Result<()> {
-    println!("Processing {}", path.display());
+/// The systems of the config file, or the known ones. Without either a
+/// config file or --systems, only the default ones, unless all is set.
+fn system_configs(args: &ArgMatches, all: bool) -> io::Result<Vec<SystemConfig>> {
+    let mut configs = match args.value_of("config") {
+        Some(path) => config::load(Path::new(path))?,
+        None if all || args.is_present("systems") => config::all_configs(),
+        None => config::default_configs(),
+    };
 
-    let input_size = std::fs::metadata(&path)?.len();
-    let pb = ProgressBar::new(input_size);
-    pb.set_draw_target(ProgressDrawTarget::stderr());
+    if let Some(names) = args.values_of("systems") {
+        let names: Vec<_> = names.collect();
+        for name in names.iter() {
+            if !configs.iter().any(|config| config.name() == *name) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("No rating system named {}", name),
+                ));
+            }
+        }
+        configs.retain(|config| names.contains(&config.name()));
+    }
 
-    let input_file = File::open(path)?;
-    let pbr = ProgressBarRead::new(&pb, input_file);
-    let decoder = zstd::Decoder::new(pbr)?;
+    if let Some(settings) = args.values_of("set") {
+        for setting in settings {
+            config::apply_override(&mut configs, setting)?;
+        }
+    }
 
-    pb.set_style(
-        indicatif::ProgressStyle::default_bar().template(
-            "[{elapsed_precise}] {spinner} {wide_bar} {bytes}/{total_bytes} {msg} [{eta}]",
-        ),
-    );
-
-    let f = BufReader::new(decoder);
-    let mut pgn_buff = String::from("");
-    let mut empty = 0;
-    let mut counter: u64 = 0;
-
-    for line in f.lines() {
-        if line.is_err() {
-            return line.map(|_| ());
-        }
-        let line = line.unwrap();
-        if line.is_empty() {
-            empty += 1;
-        }
-        if empty == 2 {
-            process_game(&pgn_buff, db);
-            empty = 0;
-            pgn_buff.clear();
-
-            counter += 1;
-            if counter % 10000 == 0 {
-                pb.tick();
-                let players = db.player_count();
-                pb.set_message(&format!("{} games, {} players", counter, players));
-            }
-        } else {
-            pgn_buff.push_str(&line);
-            pgn_buff.push_str("\n");
+    Ok(configs)
+}
+
+fn selected_systems(args: &ArgMatches) -> io::Result<Vec<SystemInstance>> {
+    config::instantiate(&system_configs(args, false)?)
+}
+
+/// Run all input files through a fresh RatingDB, printing the prediction
+/// stats after each file.
+fn run_inputs(args: &ArgMatches) -> io::Result<RatingDB> {
+    let paths = input_paths(args)?;
+    let speeds = selected_speeds(args);
+    let threads = selected_threads(args)?;
+    let resamples = selected_resamples(args)?;
+    let mut db = match args.value_of("resume") {
+        Some(path) => RatingDB::load_snapshot(Path::new(path), selected_systems(args)?)?,
+        None => RatingDB::new(selected_systems(args)?),
+    };
+    if resamples.is_some() {
+        db.keep_games();
+    }
+    if args.is_present("strata") {
+        db.keep_strata();
+    }
+    db.set_perspective(Perspective::from_name(args.value_of("perspective").unwrap()).unwrap());
+    db.set_update_order(UpdateOrder::from_name(args.value_of("update-order").unwrap()).unwrap());
+    if let Some(path) = args.value_of("history") {
+        let players = args
+            .values_of("history-players")
+            .map(|players| players.map(String::from).collect::<HashSet<_>>());
+        let history = HistoryRecorder::create(
+            Path::new(path),
+            db.systems(),
+            players,
+            args.is_present("resume"),
+        )?;
+        db.record_history(history);
+    }
+
+    for path in paths {
+        input::process_file(&path, &mut db, &speeds, threads)?;
+        println!(
+            "{}",
+            db.get_stats(args.is_present("calibration"), resamples)
+        );
+        db.stats_reset();
+        db.flush_history()?;
+        if let Some(path) = args.value_of("save-snapshot") {
+            db.save_snapshot(Path::new(path))?;
         }
     }
 
+    Ok(db)
+}
+
+fn optimize(args: &ArgMatches) -> io::Result<()> {
+    let name = args.value_of("system").unwrap();
+    let system = system_configs(args, true)?
+        .into_iter()
+        .find(|config| config.name() == name)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("No rating system named {}", name),
+            )
+        })?;
+    let parse_count = |arg: &str| {
+        args.value_of(arg)
+            .unwrap()
+            .parse::<usize>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))
+    };
+    let optimization = Optimization {
+        system,
+        params: args.values_of("param").unwrap().map(String::from).collect(),
+        metric: Metric::from_name(args.value_of("metric").unwrap()).unwrap(),
+        max_evals: parse_count("max-evals")?,
+        warmup: parse_count("warmup")?,
+        holdout: parse_count("holdout")?,
+    };
+
+    // Every evaluation replays all games, so keep them in memory
+    let speeds = selected_speeds(args);
+    let threads = selected_threads(args)?;
+    let mut files = vec![];
+    for path in input_paths(args)? {
+        let mut games = vec![];
+        input::process_file(&path, &mut games, &speeds, threads)?;
+        files.push(games);
+    }
+
+    optimization.run(&files, args.value_of("trajectory"))
+}
+
+/// Replay the games with one rating system and compare its rating changes
+/// to the ones lichess recorded in the games.
+fn verify(args: &ArgMatches) -> io::Result<()> {
+    let name = args.value_of("system").unwrap();
+    let system = system_configs(args, true)?
+        .into_iter()
+        .find(|config| config.name() == name)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("No rating system named {}", name),
+            )
+        })?;
+    let speeds = selected_speeds(args);
+    let threads = selected_threads(args)?;
+    let mut verifier = Verifier::new(config::instantiate(&[system])?.remove(0));
+
+    for path in input_paths(args)? {
+        input::process_file(&path, &mut verifier, &speeds, threads)?;
+        println!("{}", verifier.get_stats());
+        verifier.stats_reset();
+    }
+
+    Ok(())
+}
+
+/// Fit Whole-History Ratings, refitting after each file and printing how
+/// well the fitted ratings explain the games of the file.
+fn whole_history(args: &ArgMatches) -> io::Result<()> {
+    let parse = |arg: &str| {
+        args.value_of(arg)
+            .unwrap()
+            .parse::<f64>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))
+    };
+    let iterations = args
+        .value_of("iterations")
+        .unwrap()
+        .parse::<usize>()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    let params = WhrParams {
+        w2: parse("w2")?,
+        prior_games: parse("prior-games")?,
+        iterations,
+    };
+    let speeds = selected_speeds(args);
+    let threads = selected_threads(args)?;
+    let mut whr = Whr::new(params)?;
+
+    for path in input_paths(args)? {
+        input::process_file(&path, &mut whr, &speeds, threads)?;
+        whr.refit();
+        println!("{}", whr.get_stats());
+        whr.stats_reset();
+    }
+
+    if let Some(output) = args.value_of("output") {
+        whr.dump_report(output);
+    }
+    Ok(())
+}
+
+fn print_report(args: &ArgMatches) -> io::Result<()> {
+    let top = args
+        .value_of("top")
+        .unwrap()
+        .parse::<usize>()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    let speed = TimeControl::from_name(args.value_of("speed").unwrap()).unwrap();
+    let path = playerdb::report_path(args.value_of("REPORT").unwrap(), &speed);
+    let report = BufReader::new(File::open(path)?);
+
+    for (rank, line) in report.lines().take(top).enumerate() {
+        let line = line?;
+        let mut fields = line.splitn(2, ',');
+        let name = fields.next().unwrap_or("");
+        let ratings = fields.next().unwrap_or("");
+        println!("{:>6} {:<30} {}", rank + 1, name, ratings);
+    }
+
     Ok(())
 }
 
+fn systems_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("systems")
+        .long("systems")
+        .help("Rating systems to run, by name [default: G1,G2,L2,E]")
+        .takes_value(true)
+        .use_delimiter(true)
+        .multiple(true)
+}
+
+fn threads_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("threads")
+        .short("j")
+        .long("threads")
+        .help("Number of threads parsing PGNs [default: number of cores]")
+        .takes_value(true)
+}
+
+fn update_order_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("update-order")
+        .long("update-order")
+        .help(
+            "Update both players from their ratings before the game, or white first and black \
+             against white's new rating, as older versions did",
+        )
+        .takes_value(true)
+        .possible_values(UpdateOrder::NAMES)
+        .default_value("simultaneous")
+}
+
+fn perspective_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("perspective")
+        .long("perspective")
+        .help("Whose predictions to score: white's, black's, both players' or a random one's")
+        .takes_value(true)
+        .possible_values(Perspective::NAMES)
+        .default_value("white")
+}
+
+fn calibration_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("calibration")
+        .long("calibration")
+        .help("Print expected against observed score in 5% buckets for every system")
+}
+
+fn strata_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("strata").long("strata").help(
+        "Print the games, MSE and log-loss of every system by rating, number of prior games, \
+         RD, rating difference and time since the last game",
+    )
+}
+
+fn bootstrap_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("bootstrap")
+        .long("bootstrap")
+        .value_name("RESAMPLES")
+        .help(
+            "Keep the loss of every game, and print bootstrap confidence intervals from this \
+             many resamples and Diebold-Mariano tests between every two systems",
+        )
+        .takes_value(true)
+}
+
+fn resume_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("resume")
+        .long("resume")
+        .value_name("SNAPSHOT")
+        .help("Start from the ratings in a snapshot, written with --save-snapshot")
+        .takes_value(true)
+}
+
+fn save_snapshot_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("save-snapshot")
+        .long("save-snapshot")
+        .value_name("SNAPSHOT")
+        .help("Save a snapshot of the ratings after every file, to resume from later")
+        .takes_value(true)
+}
+
+fn history_args<'a, 'b>() -> [Arg<'a, 'b>; 2] {
+    [
+        Arg::with_name("history")
+            .long("history")
+            .value_name("CSV")
+            .help("Write the ratings of the players after every game to a CSV file")
+            .takes_value(true),
+        Arg::with_name("history-players")
+            .long("history-players")
+            .help("Only write the history of these players")
+            .takes_value(true)
+            .use_delimiter(true)
+            .require_delimiter(true)
+            .multiple(true)
+            .requires("history"),
+    ]
+}
+
+fn input_args<'a, 'b>(cmd: App<'a, 'b>) -> App<'a, 'b> {
+    cmd.arg(
+        Arg::with_name("INPUT")
+            .help("PGN files or game caches, or globs, processed in sorted order. - reads stdin")
+            .required(true)
+            .multiple(true),
+    )
+    .arg(
+        Arg::with_name("config")
+            .short("c")
+            .long("config")
+            .help("TOML or JSON file listing the rating systems to run and their parameters")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("set")
+            .long("set")
+            .help("Override a parameter, as NAME.KEY=VALUE")
+            .takes_value(true)
+            .number_of_values(1)
+            .multiple(true),
+    )
+    .arg(
+        Arg::with_name("speeds")
+            .long("speeds")
+            .help("Time controls to include")
+            .takes_value(true)
+            .use_delimiter(true)
+            .multiple(true)
+            .possible_values(TimeControl::NAMES)
+            .default_value("blitz"),
+    )
+    .arg(threads_arg())
+}
+
 fn main() -> io::Result<()> {
-    const BASEDIR: &str = "/srv/large/PGN/";
-    const BASEPREFIX: &str = "lichess_db_standard_rated_";
-    const REPORT: &str = "ratings.txt";
+    let matches = App::new("rratings")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about(env!("CARGO_PKG_DESCRIPTION"))
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            input_args(
+                SubCommand::with_name("rate")
+                    .about("Rate all players and write the final ratings to a report"),
+            )
+            .arg(systems_arg())
+            .arg(calibration_arg())
+            .arg(bootstrap_arg())
+            .arg(strata_arg())
+            .arg(perspective_arg())
+            .arg(update_order_arg())
+            .arg(resume_arg())
+            .arg(save_snapshot_arg())
+            .args(&history_args())
+            .arg(
+                Arg::with_name("output")
+                    .short("o")
+                    .long("output")
+                    .help("Report file to write, one per time control as ratings-blitz.txt")
+                    .takes_value(true)
+                    .default_value("ratings.txt"),
+            ),
+        )
+        .subcommand(
+            input_args(
+                SubCommand::with_name("evaluate")
+                    .about("Measure the prediction accuracy of the rating systems"),
+            )
+            .arg(systems_arg())
+            .arg(calibration_arg())
+            .arg(bootstrap_arg())
+            .arg(strata_arg())
+            .arg(perspective_arg())
+            .arg(update_order_arg())
+            .arg(resume_arg())
+            .arg(save_snapshot_arg())
+            .args(&history_args()),
+        )
+        .subcommand(
+            input_args(
+                SubCommand::with_name("optimize")
+                    .about("Search the parameters of a rating system that minimize its loss"),
+            )
+            .arg(
+                Arg::with_name("system")
+                    .long("system")
+                    .help("Rating system to optimize, by name")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("param")
+                    .short("p")
+                    .long("param")
+                    .help("Parameter to optimize, starting from its configured value")
+                    .takes_value(true)
+                    .number_of_values(1)
+                    .multiple(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("metric")
+                    .long("metric")
+                    .help("Loss to minimize")
+                    .takes_value(true)
+                    .possible_values(Metric::NAMES)
+                    .default_value("mse"),
+            )
+            .arg(
+                Arg::with_name("max-evals")
+                    .long("max-evals")
+                    .help("Maximum number of parameter sets to try")
+                    .takes_value(true)
+                    .default_value("100"),
+            )
+            .arg(
+                Arg::with_name("warmup")
+                    .long("warmup")
+                    .help("Number of leading files that only seed the ratings")
+                    .takes_value(true)
+                    .default_value("0"),
+            )
+            .arg(
+                Arg::with_name("holdout")
+                    .long("holdout")
+                    .help("Number of trailing files used to score the best parameters")
+                    .takes_value(true)
+                    .default_value("1"),
+            )
+            .arg(
+                Arg::with_name("trajectory")
+                    .long("trajectory")
+                    .help("CSV file to write every evaluated parameter set to")
+                    .takes_value(true),
+            ),
+        )
+        .subcommand(
+            input_args(
+                SubCommand::with_name("verify")
+                    .about("Compare the rating changes of a rating system to the ones in the PGNs"),
+            )
+            .arg(
+                Arg::with_name("system")
+                    .long("system")
+                    .help("Rating system to verify, by name")
+                    .takes_value(true)
+                    .default_value("LG"),
+            ),
+        )
+        .subcommand(
+            input_args(
+                SubCommand::with_name("whr")
+                    .about("Fit Whole-History Ratings, the best possible fit in hindsight"),
+            )
+            .arg(
+                Arg::with_name("w2")
+                    .long("w2")
+                    .help("Variance of the rating change, in Elo^2 per day")
+                    .takes_value(true)
+                    .default_value("14"),
+            )
+            .arg(
+                Arg::with_name("prior-games")
+                    .long("prior-games")
+                    .help("Virtual wins and losses against a 1500 player pulling new players in")
+                    .takes_value(true)
+                    .default_value("1"),
+            )
+            .arg(
+                Arg::with_name("iterations")
+                    .long("iterations")
+                    .help("Newton passes over the players of each file")
+                    .takes_value(true)
+                    .default_value("10"),
+            )
+            .arg(
+                Arg::with_name("output")
+                    .short("o")
+                    .long("output")
+                    .help("Report file to write the final ratings to, one per time control")
+                    .takes_value(true),
+            ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Convert PGN files to compact game caches for faster repeated runs")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("PGN files or globs. - reads stdin")
+                        .required(true)
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .help("Directory to write the caches to")
+                        .takes_value(true)
+                        .default_value("."),
+                )
+                .arg(threads_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("report")
+                .about("Show the top of a report written by rate")
+                .arg(
+                    Arg::with_name("REPORT")
+                        .help("Report file given to rate")
+                        .default_value("ratings.txt"),
+                )
+                .arg(
+                    Arg::with_name("speed")
+                        .long("speed")
+                        .help("Time control whose report to read")
+                        .takes_value(true)
+                        .possible_values(TimeControl::NAMES)
+                        .default_value("blitz"),
+                )
+                .arg(
+                    Arg::with_name("top")
+                        .short("n")
+                        .long("top")
+                        .help("Number of players to show")
+                        .takes_value(true)
+                        .default_value("50"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("history")
+                .about("Show the rating history of a player, written by rate --history")
+                .arg(
+                    Arg::with_name("HISTORY")
+                        .help("History file given to rate")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("player")
+                        .long("player")
+                        .help("Player to show")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("speed")
+                        .long("speed")
+                        .help("Only show this time control [default: all]")
+                        .takes_value(true)
+                        .possible_values(TimeControl::NAMES),
+                ),
+        )
+        .get_matches();
+
+    match matches.subcommand() {
+        ("rate", Some(args)) => {
+            let db = run_inputs(args)?;
+            db.dump_report(args.value_of("output").unwrap());
+            Ok(())
+        }
+        ("evaluate", Some(args)) => run_inputs(args).map(|_| ()),
+        ("verify", Some(args)) => verify(args),
+        ("whr", Some(args)) => whole_history(args),
+        ("optimize", Some(args)) => optimize(args),
+        ("import", Some(args)) => import(args),
+        ("report", Some(args)) => print_report(args),
+        ("history", Some(args)) => history::print_history(
+            Path::new(args.value_of("HISTORY").unwrap()),
+            args.value_of("player").unwrap(),
+            args.value_of("speed").and_then(TimeControl::from_name),
+        ),
+        _ => unreachable!(),
+    }
+}
 
-    let input_glob = String::from(BASEDIR) + BASEPREFIX + "*.zst";
+#[cfg(test)]
+mod tests {
+    use super::{parse_game, Clock, ParseError, TimeControl};
 
-    let mut paths: Vec<_> = glob(&input_glob).unwrap().filter_map(Result::ok).collect();
-    paths.sort();
+    fn clock(base: u32, increment: u32) -> Clock {
+        Clock { base, increment }
+    }
 
-    let mut db = RatingDB::new();
+    fn game(event: &str, time_control: &str) -> String {
+        format!(
+            "[Event \"{}\"]\n[White \"a\"]\n[Black \"b\"]\n[Result \"1-0\"]\n\
+             [UTCDate \"2018.01.01\"]\n[UTCTime \"00:00:00\"]\n[TimeControl \"{}\"]\n\n1-0\n",
+            event, time_control
+        )
+    }
 
-    for path in paths {
-        process_zstd_pgn(path, &mut db)?;
-        println!("{}", db.get_stats());
-        db.stats_reset();
+    #[test]
+    fn clock_parses_base_and_increment() {
+        assert_eq!(Clock::parse("180+2"), Ok(Some(clock(180, 2))));
+        assert_eq!(Clock::parse("-"), Ok(None));
+        assert_eq!(Clock::parse("?"), Ok(None));
+        assert_eq!(Clock::parse("180+x"), Err(ParseError::BadClock));
+        assert_eq!(Clock::parse("-1+0"), Err(ParseError::BadClock));
+        assert_eq!(Clock::parse(""), Err(ParseError::BadClock));
     }
 
-    db.dump_report(REPORT);
+    #[test]
+    fn clock_parses_other_pgn_forms() {
+        assert_eq!(Clock::parse("180"), Ok(Some(clock(180, 0))));
+        assert_eq!(Clock::parse("*180"), Ok(Some(clock(180, 0))));
+        assert_eq!(Clock::parse("40/7200:3600"), Ok(Some(clock(7200, 0))));
+        assert_eq!(
+            Clock::parse("40/5400+30:1800+30"),
+            Ok(Some(clock(5400, 30)))
+        );
+        assert_eq!(Clock::parse("x/7200"), Err(ParseError::BadClock));
+        assert_eq!(Clock::parse("40/7200:x"), Err(ParseError::BadClock));
+    }
 
-    Ok(())
+    #[test]
+    fn speed_follows_estimated_duration() {
+        assert_eq!(clock(15, 0).speed(), TimeControl::UltraBullet);
+        assert_eq!(clock(60, 0).speed(), TimeControl::Bullet);
+        // 120 + 40 * 1 = 160 seconds
+        assert_eq!(clock(120, 1).speed(), TimeControl::Bullet);
+        assert_eq!(clock(180, 0).speed(), TimeControl::Blitz);
+        assert_eq!(clock(180, 2).speed(), TimeControl::Blitz);
+        assert_eq!(clock(300, 5).speed(), TimeControl::Rapid);
+        assert_eq!(clock(1500, 0).speed(), TimeControl::Classical);
+    }
+
+    #[test]
+    fn huge_increment_does_not_overflow() {
+        assert_eq!(clock(0, u32::MAX).speed(), TimeControl::Classical);
+        assert_eq!(clock(u32::MAX, u32::MAX).speed(), TimeControl::Classical);
+    }
+
+    #[test]
+    fn time_control_header_sets_speed() {
+        let update = parse_game(game("Rated Bullet game", "180+2").as_bytes()).unwrap();
+        assert_eq!(update.speed, TimeControl::Blitz);
+        assert_eq!(update.clock, Some(clock(180, 2)));
+
+        let update = parse_game(game("Rated Correspondence game", "-").as_bytes()).unwrap();
+        assert_eq!(update.speed, TimeControl::Correspondence);
+        assert_eq!(update.clock, None);
+
+        // An unknown clock leaves the speed to the event, like no header
+        let update = parse_game(game("Rated Rapid game", "?").as_bytes()).unwrap();
+        assert_eq!(update.speed, TimeControl::Rapid);
+        let update = parse_game(game("Rated game", "40/7200:3600").as_bytes()).unwrap();
+        assert_eq!(update.speed, TimeControl::Classical);
+
+        let bad = game("Rated Blitz game", "x");
+        assert_eq!(parse_game(bad.as_bytes()), Err(ParseError::BadClock));
+    }
+
+    #[test]
+    fn unexpected_headers_reject_the_game() {
+        let blitz = game("Rated Blitz game", "180+0");
+        let parse = |pgn: &str| parse_game(pgn.as_bytes());
+        assert_eq!(parse(""), Err(ParseError::Empty));
+        let result = blitz.replace("[Result \"1-0\"]", "[Result \"*\"]");
+        assert_eq!(parse(&result), Err(ParseError::NoResult));
+        let date = blitz.replace("2018.01.01", "2018.13.01");
+        assert_eq!(parse(&date), Err(ParseError::BadDate));
+        assert_eq!(
+            parse(&game("Casual Blitz game", "180+0")),
+            Err(ParseError::NotRated)
+        );
+        assert_eq!(
+            parse(&game("Unrated Blitz game", "180+0")),
+            Err(ParseError::Unrated)
+        );
+        let mut name = blitz.into_bytes();
+        let white = name.windows(8).position(|w| w == b"White \"a").unwrap();
+        name[white + 7] = 0xff;
+        assert_eq!(parse_game(&name), Err(ParseError::NotUtf8));
+    }
+
+    #[test]
+    fn bad_elo_headers_keep_the_game() {
+        let pgn = game("Rated Blitz game", "180+0").replace(
+            "[White \"a\"]",
+            "[White \"a\"]\n[WhiteElo \"?\"]\n[BlackElo \"1500\"]\n[WhiteRatingDiff \"x\"]",
+        );
+        let update = parse_game(pgn.as_bytes()).unwrap();
+        assert_eq!(update.white_rating, None);
+        assert_eq!(update.black_rating, Some(1500));
+        assert_eq!(update.white_rating_diff, None);
+    }
 }
@@ -0,0 +1,332 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::slice;
+
+use config::{self, SystemConfig};
+use playerdb::{RatingDB, SystemStats};
+
+use super::ResultUpdate;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Metric {
+    Mse,
+    LogLoss,
+}
+
+impl Metric {
+    pub const NAMES: &'static [&'static str] = &["mse", "logloss"];
+
+    pub fn from_name(name: &str) -> Option<Metric> {
+        match name {
+            "mse" => Some(Metric::Mse),
+            "logloss" => Some(Metric::LogLoss),
+            _ => None,
+        }
+    }
+
+    fn of(self, stats: &SystemStats) -> f64 {
+        let loss = match self {
+            Metric::Mse => stats.mse(),
+            Metric::LogLoss => stats.log_loss(),
+        };
+        // Diverged parameters must never look good
+        if loss.is_nan() {
+            f64::INFINITY
+        } else {
+            loss
+        }
+    }
+}
+
+/// Minimize f with the Nelder-Mead downhill simplex method. The initial
+/// simplex extends step from start along every axis. Returns the best point
+/// and its value, or the first error of f.
+pub fn nelder_mead<F, E>(
+    start: &[f64],
+    step: f64,
+    max_evals: usize,
+    mut f: F,
+) -> Result<(Vec<f64>, f64), E>
+where
+    F: FnMut(&[f64]) -> Result<f64, E>,
+{
+    const REFLECT: f64 = 1.0;
+    const EXPAND: f64 = 2.0;
+    const CONTRACT: f64 = 0.5;
+    const SHRINK: f64 = 0.5;
+    const TOLERANCE: f64 = 1e-6;
+
+    let n = start.len();
+    let mut simplex = vec![start.to_vec()];
+    for i in 0..n {
+        let mut vertex = start.to_vec();
+        vertex[i] += step;
+        simplex.push(vertex);
+    }
+    let mut values = simplex
+        .iter()
+        .map(|x| f(x))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut evals = n + 1;
+
+    // Point on the line from the centroid through x, at distance scale
+    let along = |centroid: &[f64], x: &[f64], scale: f64| -> Vec<f64> {
+        centroid
+            .iter()
+            .zip(x.iter())
+            .map(|(c, x)| c + scale * (x - c))
+            .collect()
+    };
+
+    while evals < max_evals {
+        let mut order: Vec<usize> = (0..=n).collect();
+        order.sort_by(|&a, &b| values[a].partial_cmp(&values[b]).unwrap());
+        simplex = order.iter().map(|&i| simplex[i].clone()).collect();
+        values = order.iter().map(|&i| values[i]).collect();
+
+        if (values[n] - values[0]).abs() < TOLERANCE {
+            break;
+        }
+
+        let mut centroid = vec![0.0; n];
+        for vertex in simplex.iter().take(n) {
+            for (c, x) in centroid.iter_mut().zip(vertex.iter()) {
+                *c += x / n as f64;
+            }
+        }
+
+        let reflected = along(&centroid, &simplex[n], -REFLECT);
+        let reflected_value = f(&reflected)?;
+        evals += 1;
+
+        if reflected_value < values[0] {
+            let expanded = along(&centroid, &simplex[n], -EXPAND);
+            let expanded_value = f(&expanded)?;
+            evals += 1;
+            if expanded_value < reflected_value {
+                simplex[n] = expanded;
+                values[n] = expanded_value;
+            } else {
+                simplex[n] = reflected;
+                values[n] = reflected_value;
+            }
+        } else if reflected_value < values[n - 1] {
+            simplex[n] = reflected;
+            values[n] = reflected_value;
+        } else {
+            let contracted = along(&centroid, &simplex[n], CONTRACT);
+            let contracted_value = f(&contracted)?;
+            evals += 1;
+            if contracted_value < values[n] {
+                simplex[n] = contracted;
+                values[n] = contracted_value;
+            } else {
+                for i in 1..=n {
+                    simplex[i] = along(&simplex[0], &simplex[i], SHRINK);
+                    values[i] = f(&simplex[i])?;
+                    evals += 1;
+                }
+            }
+        }
+    }
+
+    let best = (0..=n)
+        .min_by(|&a, &b| values[a].partial_cmp(&values[b]).unwrap())
+        .unwrap();
+    Ok((simplex[best].clone(), values[best]))
+}
+
+/// Run the games through a fresh RatingDB with only the given system,
+/// and score the predictions made during the scored games. Fails with
+/// InvalidInput for parameters the system does not accept.
+fn score(
+    system: &SystemConfig,
+    warmup: &[Vec<ResultUpdate>],
+    scored: &[Vec<ResultUpdate>],
+    metric: Metric,
+) -> io::Result<f64> {
+    let mut db = RatingDB::new(config::instantiate(slice::from_ref(system))?);
+    replay(&mut db, warmup);
+    db.stats_reset();
+    replay(&mut db, scored);
+    Ok(metric.of(&db.system_stats(0)))
+}
+
+fn replay(db: &mut RatingDB, files: &[Vec<ResultUpdate>]) {
+    for games in files {
+        // Games read from a cache carry the player ids of that file
+        db.forget_cache_ids();
+        for game in games {
+            db.update(game);
+        }
+    }
+}
+
+/// Parameters that can have any sign. They are searched on a linear scale,
+/// in units of 100 rating points, instead of in log space.
+const SIGNED_PARAMS: &[&str] = &["white_advantage"];
+const SIGNED_UNIT: f64 = 100.0;
+
+pub struct Optimization {
+    pub system: SystemConfig,
+    pub params: Vec<String>,
+    pub metric: Metric,
+    pub max_evals: usize,
+    /// Number of leading files that only seed the ratings
+    pub warmup: usize,
+    /// Number of trailing files kept out of the search to score the result
+    pub holdout: usize,
+}
+
+impl Optimization {
+    /// Search the parameters over the per-file games. The search runs in
+    /// log space, as the parameters are positive and of differing scale,
+    /// except for SIGNED_PARAMS.
+    pub fn run(&self, files: &[Vec<ResultUpdate>], trajectory: Option<&str>) -> io::Result<()> {
+        if self.warmup + self.holdout >= files.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Need more than {} input files for {} warmup and {} holdout files",
+                    files.len(),
+                    self.warmup,
+                    self.holdout
+                ),
+            ));
+        }
+        let train_end = files.len() - self.holdout;
+        let warmup = &files[..self.warmup];
+        let train = &files[self.warmup..train_end];
+
+        let defaults = config::instantiate(slice::from_ref(&self.system))?
+            .remove(0)
+            .params;
+        let signed: Vec<bool> = self
+            .params
+            .iter()
+            .map(|param| SIGNED_PARAMS.contains(&&param[..]))
+            .collect();
+        let mut start = vec![];
+        for (param, signed) in self.params.iter().zip(signed.iter()) {
+            match defaults.get(param) {
+                Some(value) if *signed => start.push(f64::from(*value) / SIGNED_UNIT),
+                Some(value) if *value > 0.0 => start.push(f64::from(*value).ln()),
+                Some(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Can only optimize positive parameters, {} is not", param),
+                    ))
+                }
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{} has no parameter {}", self.system.name(), param),
+                    ))
+                }
+            }
+        }
+
+        let mut trace = match trajectory {
+            Some(path) => {
+                let mut file = File::create(path)?;
+                writeln!(file, "eval,{},loss", self.params.join(","))?;
+                Some(file)
+            }
+            None => None,
+        };
+
+        // Parameter values at a point of the search
+        let values = |x: &[f64]| -> Vec<f32> {
+            x.iter()
+                .zip(signed.iter())
+                .map(|(x, signed)| {
+                    let value = if *signed { x * SIGNED_UNIT } else { x.exp() };
+                    value as f32
+                })
+                .collect()
+        };
+        let with_params = |x: &[f64]| {
+            let mut system = self.system.clone();
+            for (param, value) in self.params.iter().zip(values(x)) {
+                system.params.insert(param.clone(), value);
+            }
+            system
+        };
+        let format_params = |x: &[f64]| {
+            values(x)
+                .iter()
+                .map(|value| format!("{}", value))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+
+        let mut evals = 0;
+        let (best, best_loss) =
+            nelder_mead(&start, 0.25, self.max_evals, |x| -> io::Result<f64> {
+                // Combinations the system rejects, like a typical RD above the
+                // initial one, are the worst possible instead of the end
+                let loss = match score(&with_params(x), warmup, train, self.metric) {
+                    Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => f64::INFINITY,
+                    loss => loss?,
+                };
+                evals += 1;
+                println!("{:4} {} -> {:.6}", evals, format_params(x), loss);
+                if let Some(ref mut file) = trace {
+                    writeln!(file, "{},{},{}", evals, format_params(x), loss)?;
+                }
+                Ok(loss)
+            })?;
+
+        println!(
+            "Best {} = {} with training loss {:.6}",
+            self.params.join(","),
+            format_params(&best),
+            best_loss
+        );
+
+        if self.holdout > 0 {
+            let seed = &files[..train_end];
+            let holdout = &files[train_end..];
+            let before = score(&self.system, seed, holdout, self.metric)?;
+            let after = score(&with_params(&best), seed, holdout, self.metric)?;
+            println!(
+                "Held-out loss {:.6} with the initial parameters, {:.6} with the best",
+                before, after
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::nelder_mead;
+
+    #[test]
+    fn nelder_mead_finds_the_minimum_of_a_quadratic() {
+        let quadratic = |x: &[f64]| -> Result<f64, ()> {
+            Ok(3.0 + (x[0] - 1.0).powi(2) + 2.0 * (x[1] + 2.0).powi(2))
+        };
+        let (best, value) = nelder_mead(&[0.0, 0.0], 0.25, 500, quadratic).unwrap();
+        assert!((best[0] - 1.0).abs() < 1e-2, "{:?}", best);
+        assert!((best[1] + 2.0).abs() < 1e-2, "{:?}", best);
+        assert!((value - 3.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn nelder_mead_stops_at_the_first_error() {
+        let mut evals = 0;
+        let result = nelder_mead(&[0.0], 0.25, 500, |x| {
+            evals += 1;
+            if evals == 5 {
+                Err("failed")
+            } else {
+                Ok(x[0] * x[0])
+            }
+        });
+        assert_eq!(result, Err("failed"));
+        assert_eq!(evals, 5);
+    }
+}
@@ -1,272 +1,1489 @@
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::prelude::*;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, prelude::*, BufReader, BufWriter};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::sync::Mutex;
 
 use chrono::{DateTime, TimeZone, Utc};
 use pgn_reader::{Color, Outcome};
 
-use super::ResultUpdate;
-use glicko::GlickoRating;
-use glicko2::Glicko2Rating;
-use ligcko2::Ligcko2Rating;
+use super::{ResultUpdate, TimeControl};
+use advantage::WhiteAdvantage;
+use drawmodel::DrawModel;
+use history::HistoryRecorder;
+use ratingsystem::{RatingSystem, Settings, SystemInstance};
+use significance::{bootstrap, diebold_mariano, GameLoss, LossTotals, Rng};
+use strata::{self, Strata, Stratum};
 
 #[derive(Clone, Debug)]
 pub struct Player {
-    pub g1rating: GlickoRating,
-    pub g2rating: Glicko2Rating,
-    pub l2rating: Ligcko2Rating,
+    pub ratings: Vec<Box<dyn RatingSystem>>,
     pub mtime: DateTime<Utc>,
+    pub games: u32,
 }
 
 impl Player {
-    pub fn new(mtime: &DateTime<Utc>) -> Player {
+    pub fn new(mtime: &DateTime<Utc>, systems: &[SystemInstance]) -> Player {
         Player {
-            g1rating: GlickoRating::new(),
-            g2rating: Glicko2Rating::new(),
-            l2rating: Ligcko2Rating::new(),
+            ratings: systems.iter().map(|system| system.new_rating()).collect(),
             mtime: *mtime,
+            games: 0,
         }
     }
 
-    pub fn update_with_result(
-        &mut self,
+    /// Make this a copy of the other player, reusing the ratings
+    pub fn copy_from(&mut self, other: &Player) {
+        for (rating, other_rating) in self.ratings.iter_mut().zip(other.ratings.iter()) {
+            rating.copy_from(&**other_rating);
+        }
+        self.mtime = other.mtime;
+        self.games = other.games;
+    }
+
+    /// Record the predictions of every system for this player's side of
+    /// the game, before either player is updated
+    pub fn record_predictions(
+        &self,
         color: Color,
         result: &Outcome,
         result_time: &DateTime<Utc>,
         opponent: &Player,
-        stats: Option<&Mutex<StatsDB>>,
-        update: Option<&ResultUpdate>,
+        stats: &mut StatsDB,
     ) {
-        let old_time = self.mtime;
-
-        let score = match result {
-            Outcome::Draw => 0.5,
-            Outcome::Decisive { winner } => {
-                if *winner == color {
-                    1.0
-                } else {
-                    0.0
-                }
-            }
+        let score = score(color, result);
+        let outcome = if score == 1.0 {
+            0
+        } else if score == 0.5 {
+            1
+        } else {
+            2
+        };
+        let side = match color {
+            Color::White => 0,
+            Color::Black => 1,
         };
 
-        // Record predictions
-        if let Some(stats) = stats {
-            let mut stats = stats.lock().unwrap();
+        for (i, rating) in self.ratings.iter().enumerate() {
+            let opp_rating = &*opponent.ratings[i];
+            let system_stats = &mut stats.systems[i];
 
-            // Glicko-1 updates
             // Ternary expected, for compat with lichess ratings
-            let expected_score = if self.g1rating.r > opponent.g1rating.r {
+            let expected_score = if rating.rating() > opp_rating.rating() {
                 1.0f32
-            } else if self.g1rating.r < opponent.g1rating.r {
+            } else if rating.rating() < opp_rating.rating() {
                 0.0f32
             } else {
                 0.5f32
             };
-            stats.glicko_guess += 1;
-            if (score - expected_score).abs() < 0.5f32 {
-                stats.glicko_predicted += 1
+            system_stats.guess += 1;
+            let hit = (score - expected_score).abs() < 0.5f32;
+            if hit {
+                system_stats.predicted += 1
             }
 
-            // Smooth expected from Glicko formula
-            let expected_score = self.g1rating.expect(&old_time, result_time, opponent);
-            stats.glicko_mse_total += 1.0;
-            stats.glicko_mse_accum += (score - expected_score).powf(2.0) as f64;
-
-            // Glicko-2 updates
-            // Ternary expected, for compat with lichess ratings
-            let expected_score = if self.g2rating.mu > opponent.g2rating.mu {
-                1.0f32
-            } else if self.g2rating.mu < opponent.g2rating.mu {
-                0.0f32
-            } else {
-                0.5f32
+            // Smooth expected from the rating system
+            let expected_score =
+                rating.expect(color, &self.mtime, result_time, opp_rating, &opponent.mtime);
+            system_stats.record(score, expected_score);
+            let game = GameLoss {
+                hit,
+                squared_error: (score - expected_score).powf(2.0),
+                log_loss: log_loss(score, expected_score) as f32,
             };
-            stats.glicko2_guess += 1;
-            if (score - expected_score).abs() < 0.5f32 {
-                stats.glicko2_predicted += 1
+            system_stats.sides[side].add(&game);
+            if stats.keep_strata {
+                let strata = &mut system_stats.strata;
+                strata.record(Stratum::Rating, rating.rating().into(), &game);
+                strata.record(Stratum::PriorGames, self.games.into(), &game);
+                strata.record(Stratum::Deviation, rating.deviation().into(), &game);
+                let difference = rating.rating() - opp_rating.rating();
+                strata.record(Stratum::RatingDifference, difference.into(), &game);
+                if self.games > 0 {
+                    let idle = (*result_time - self.mtime).num_seconds();
+                    strata.record(Stratum::Idle, idle as f64, &game);
+                }
+            }
+            if stats.keep_games {
+                system_stats.games.push(game);
+            }
+
+            let mean_rating = (rating.rating() + opp_rating.rating()) / 2.0;
+            let draw_model = &mut stats.draw_models[i];
+            let mut probabilities = draw_model.probabilities(expected_score, mean_rating);
+            draw_model.learn(outcome, expected_score, mean_rating);
+            // Systems with their own draw probabilities are scored on those
+            if let Some(own) =
+                rating.expect_outcomes(color, &self.mtime, result_time, opp_rating, &opponent.mtime)
+            {
+                probabilities = [own[0].into(), own[1].into(), own[2].into()];
+            }
+            system_stats.draw_expected_accum += probabilities[1];
+            system_stats.log_loss3_accum += -probabilities[outcome].max(1e-6).ln();
+            if most_likely(&probabilities) == Some(outcome) {
+                system_stats.predicted3 += 1;
             }
+        }
+    }
 
-            // Smooth expected from Glicko formula
-            let expected_score = self.g2rating.expect(opponent);
-            stats.glicko2_mse_total += 1.0;
-            stats.glicko2_mse_accum += (score - expected_score).powf(2.0) as f64;
+    pub fn update_with_result(
+        &mut self,
+        color: Color,
+        result: &Outcome,
+        result_time: &DateTime<Utc>,
+        opponent: &Player,
+    ) {
+        let old_time = self.mtime;
+        let score = score(color, result);
 
-            // Ligcko-2 updates
-            // Ternary expected, for compat with lichess ratings
-            let expected_score = if self.l2rating.mu > opponent.l2rating.mu {
-                1.0f32
-            } else if self.l2rating.mu < opponent.l2rating.mu {
-                0.0f32
+        for (i, rating) in self.ratings.iter_mut().enumerate() {
+            rating.update_with_result(
+                color,
+                score,
+                &old_time,
+                result_time,
+                &*opponent.ratings[i],
+                &opponent.mtime,
+            );
+        }
+        self.mtime = *result_time;
+        self.games += 1;
+    }
+}
+
+/// Score of the given side in a game with the result
+fn score(color: Color, result: &Outcome) -> f32 {
+    match result {
+        Outcome::Draw => 0.5,
+        Outcome::Decisive { winner } => {
+            if *winner == color {
+                1.0
             } else {
-                0.5f32
-            };
-            stats.ligcko2_guess += 1;
-            if (score - expected_score).abs() < 0.5f32 {
-                stats.ligcko2_predicted += 1
+                0.0
             }
+        }
+    }
+}
 
-            // Smooth expected from Glicko formula
-            let expected_score = self.l2rating.expect(&old_time, result_time, opponent);
-            stats.ligcko2_mse_total += 1.0;
-            stats.ligcko2_mse_accum += (score - expected_score).powf(2.0) as f64;
-
-            // Lichess' own rating updates
-            if let Some(update) = update {
-                assert!(color == Color::White);
-                let expected_score = if update.white_rating > update.black_rating {
-                    1.0f32
-                } else if update.white_rating < update.black_rating {
-                    0.0f32
-                } else {
-                    0.5f32
-                };
-                stats.lichess_guess += 1;
-                if (score - expected_score).abs() < 0.5f32 {
-                    stats.lichess_predicted += 1
-                }
-            }
+/// The most likely of win, draw and loss, none if a probability isn't a
+/// number, as from a system whose parameters blow up its ratings
+fn most_likely(probabilities: &[f64; 3]) -> Option<usize> {
+    if !probabilities.iter().all(|p| p.is_finite()) {
+        return None;
+    }
+    (0..3).max_by(|a, b| probabilities[*a].total_cmp(&probabilities[*b]))
+}
+
+/// Cross-entropy of the expected score, counting a draw as half a win
+fn log_loss(score: f32, expected_score: f32) -> f64 {
+    let p = (expected_score as f64).clamp(1e-6, 1.0 - 1e-6);
+    let score = score as f64;
+    -(score * p.ln() + (1.0 - score) * (1.0 - p).ln())
+}
+
+/// Number of buckets of expected score, 5% each
+const CALIBRATION_BINS: usize = 20;
+
+/// The games whose expected score fell into one bucket
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CalibrationBin {
+    pub games: u64,
+    pub expected_accum: f64,
+    pub score_accum: f64,
+    pub score_sq_accum: f64,
+}
+
+impl CalibrationBin {
+    fn merge(&mut self, other: &CalibrationBin) {
+        self.games += other.games;
+        self.expected_accum += other.expected_accum;
+        self.score_accum += other.score_accum;
+        self.score_sq_accum += other.score_sq_accum;
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SystemStats {
+    pub guess: u64,
+    pub predicted: u64,
+    pub mse_accum: f64,
+    pub mse_total: f64,
+    pub log_loss_accum: f64,
+    /// Log-loss of the win, draw and loss probabilities of the draw model
+    pub log_loss3_accum: f64,
+    /// Games where the most likely of win, draw and loss happened
+    pub predicted3: u64,
+    pub draws: u64,
+    pub draw_expected_accum: f64,
+    pub bins: [CalibrationBin; CALIBRATION_BINS],
+    /// Split by the side the predictions were for, white and black
+    pub sides: [LossTotals; 2],
+    /// Every game, if the StatsDB keeps them
+    pub games: Vec<GameLoss>,
+    /// Split by the player and game, if the StatsDB keeps them
+    pub strata: Strata,
+}
+
+impl SystemStats {
+    /// Account for a game with the given score and expected score
+    pub fn record(&mut self, score: f32, expected_score: f32) {
+        self.mse_total += 1.0;
+        self.mse_accum += (score - expected_score).powf(2.0) as f64;
+        self.log_loss_accum += log_loss(score, expected_score);
+        if score == 0.5 {
+            self.draws += 1;
         }
 
-        // Update ratings
-        self.g1rating
-            .update_with_result(score, &old_time, result_time, opponent);
-        self.g2rating.update_with_result(score, opponent);
-        self.l2rating
-            .update_with_result(score, &old_time, result_time, opponent);
-        self.mtime = *result_time;
+        let index = (expected_score * CALIBRATION_BINS as f32) as usize;
+        let bin = &mut self.bins[index.min(CALIBRATION_BINS - 1)];
+        bin.games += 1;
+        bin.expected_accum += expected_score as f64;
+        bin.score_accum += score as f64;
+        bin.score_sq_accum += (score * score) as f64;
+    }
+
+    fn merge(&mut self, other: &SystemStats) {
+        self.guess += other.guess;
+        self.predicted += other.predicted;
+        self.mse_accum += other.mse_accum;
+        self.mse_total += other.mse_total;
+        self.log_loss_accum += other.log_loss_accum;
+        self.log_loss3_accum += other.log_loss3_accum;
+        self.predicted3 += other.predicted3;
+        self.draws += other.draws;
+        self.draw_expected_accum += other.draw_expected_accum;
+        for (bin, other) in self.bins.iter_mut().zip(other.bins.iter()) {
+            bin.merge(other);
+        }
+        for (side, other) in self.sides.iter_mut().zip(other.sides.iter()) {
+            side.merge(other);
+        }
+        self.games.extend_from_slice(&other.games);
+        self.strata.merge(&other.strata);
+    }
+
+    pub fn mse(&self) -> f64 {
+        self.mse_accum / self.mse_total
+    }
+
+    pub fn log_loss(&self) -> f64 {
+        self.log_loss_accum / self.mse_total
+    }
+
+    pub fn log_loss3(&self) -> f64 {
+        self.log_loss3_accum / self.mse_total
+    }
+
+    /// Murphy's split of the Brier score (the MSE) into reliability,
+    /// resolution and uncertainty, over the calibration buckets. The
+    /// Brier score is about reliability - resolution + uncertainty, and a
+    /// high reliability term means the expected scores are off.
+    pub fn brier_decomposition(&self) -> (f64, f64, f64) {
+        let total = self.mse_total;
+        let score: f64 = self.bins.iter().map(|bin| bin.score_accum).sum();
+        let score_sq: f64 = self.bins.iter().map(|bin| bin.score_sq_accum).sum();
+        let mean_score = score / total;
+
+        let mut reliability = 0.0;
+        let mut resolution = 0.0;
+        for bin in self.bins.iter().filter(|bin| bin.games > 0) {
+            let games = bin.games as f64;
+            let expected = bin.expected_accum / games;
+            let observed = bin.score_accum / games;
+            reliability += games * (expected - observed).powf(2.0);
+            resolution += games * (observed - mean_score).powf(2.0);
+        }
+        let uncertainty = score_sq / total - mean_score * mean_score;
+
+        (reliability / total, resolution / total, uncertainty)
+    }
+
+    /// Expected against observed score per bucket
+    pub fn calibration_table(&self) -> String {
+        let mut out = String::new();
+        for (i, bin) in self.bins.iter().enumerate() {
+            if bin.games == 0 {
+                continue;
+            }
+            let games = bin.games as f64;
+            out += &format!(
+                "  {:3}-{:3}%: {:8} games, expected {:6.2}%, observed {:6.2}%\n",
+                i * 100 / CALIBRATION_BINS,
+                (i + 1) * 100 / CALIBRATION_BINS,
+                bin.games,
+                100.0 * bin.expected_accum / games,
+                100.0 * bin.score_accum / games
+            );
+        }
+        out
     }
 }
 
+/// Width of the rating bands the observed draw rate is reported for
+const DRAW_BAND_WIDTH: i32 = 200;
+
 pub struct StatsDB {
-    pub glicko_guess: u64,
-    pub glicko_predicted: u64,
-    pub glicko_mse_accum: f64,
-    pub glicko_mse_total: f64,
-    pub glicko2_guess: u64,
-    pub glicko2_predicted: u64,
-    pub glicko2_mse_accum: f64,
-    pub glicko2_mse_total: f64,
-    pub ligcko2_guess: u64,
-    pub ligcko2_predicted: u64,
-    pub ligcko2_mse_accum: f64,
-    pub ligcko2_mse_total: f64,
+    pub systems: Vec<SystemStats>,
+    /// Whether to keep the loss of every game, for the significance tests
+    pub keep_games: bool,
+    /// Whether to split the stats by rating, experience and so on
+    pub keep_strata: bool,
+    /// Learned over all games, so kept over resets
+    pub draw_models: Vec<DrawModel>,
+    pub white_advantage: WhiteAdvantage,
     pub lichess_guess: u64,
     pub lichess_predicted: u64,
+    /// Games and draws by the mean lichess rating of the players
+    pub draw_bands: BTreeMap<i32, (u64, u64)>,
 }
 
 impl StatsDB {
-    pub fn new() -> StatsDB {
+    pub fn new(system_count: usize, keep_games: bool, keep_strata: bool) -> StatsDB {
         StatsDB {
-            glicko_guess: 0,
-            glicko_predicted: 0,
-            glicko_mse_accum: 0.0,
-            glicko_mse_total: 0.0,
-            glicko2_guess: 0,
-            glicko2_predicted: 0,
-            glicko2_mse_accum: 0.0,
-            glicko2_mse_total: 0.0,
-            ligcko2_guess: 0,
-            ligcko2_predicted: 0,
-            ligcko2_mse_accum: 0.0,
-            ligcko2_mse_total: 0.0,
+            systems: vec![SystemStats::default(); system_count],
+            keep_games,
+            keep_strata,
+            draw_models: vec![DrawModel::default(); system_count],
+            white_advantage: WhiteAdvantage::default(),
             lichess_guess: 0,
             lichess_predicted: 0,
+            draw_bands: BTreeMap::new(),
+        }
+    }
+
+    /// Turn the losses of both sides of the last game into one row, their
+    /// mean, so the significance tests don't take the two sides of a game
+    /// as independent games. Both sides have the same hit.
+    fn merge_sides(&mut self) {
+        for system in self.systems.iter_mut() {
+            let black = system.games.pop().unwrap();
+            let white = system.games.last_mut().unwrap();
+            white.squared_error = (white.squared_error + black.squared_error) / 2.0;
+            white.log_loss = (white.log_loss + black.log_loss) / 2.0;
+        }
+    }
+
+    /// Account for the game in the stats that don't depend on the rating
+    /// systems: lichess' own predictions, white's advantage and the draws.
+    /// Only games where both lichess ratings are known count.
+    fn record_lichess(&mut self, update: &ResultUpdate) {
+        let (white_rating, black_rating) = match (update.white_rating, update.black_rating) {
+            (Some(white_rating), Some(black_rating)) => (white_rating, black_rating),
+            _ => return,
+        };
+        let score = score(Color::White, &update.result.unwrap());
+        let rating_diff = white_rating - black_rating;
+        self.white_advantage
+            .learn(f64::from(rating_diff), f64::from(score));
+        let band = (white_rating + black_rating) / 2 / DRAW_BAND_WIDTH;
+        let band = self.draw_bands.entry(band * DRAW_BAND_WIDTH).or_default();
+        band.0 += 1;
+        if score == 0.5 {
+            band.1 += 1;
+        }
+
+        let expected_score = if white_rating > black_rating {
+            1.0f32
+        } else if white_rating < black_rating {
+            0.0f32
+        } else {
+            0.5f32
+        };
+        self.lichess_guess += 1;
+        if (score - expected_score).abs() < 0.5f32 {
+            self.lichess_predicted += 1
         }
     }
 }
 
-type MapType = HashMap<String, Player>;
+/// Whose predictions the stats are about: white's (as always before),
+/// black's, both players' or a random one's in every game
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Perspective {
+    White,
+    Black,
+    Both,
+    Random,
+}
 
-pub struct RatingDB {
-    db: Mutex<MapType>,
+impl Perspective {
+    pub const NAMES: &'static [&'static str] = &["white", "black", "both", "random"];
+
+    pub fn from_name(name: &str) -> Option<Perspective> {
+        match name {
+            "white" => Some(Perspective::White),
+            "black" => Some(Perspective::Black),
+            "both" => Some(Perspective::Both),
+            "random" => Some(Perspective::Random),
+            _ => None,
+        }
+    }
+}
+
+/// How the two players of a game are updated
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UpdateOrder {
+    /// Both from their ratings before the game
+    Simultaneous,
+    /// White first, then black against white's new rating and game time,
+    /// as rratings used to
+    Sequential,
+}
+
+impl UpdateOrder {
+    pub const NAMES: &'static [&'static str] = &["simultaneous", "sequential"];
+
+    pub fn from_name(name: &str) -> Option<UpdateOrder> {
+        match name {
+            "simultaneous" => Some(UpdateOrder::Simultaneous),
+            "sequential" => Some(UpdateOrder::Sequential),
+            _ => None,
+        }
+    }
+}
+
+/// Maps player names to dense ids, so per-player data can live in a Vec
+/// instead of a map keyed by name. Every name is stored once, shared by the
+/// map and the Vec.
+#[derive(Default)]
+pub struct PlayerNames {
+    ids: HashMap<Rc<str>, u32>,
+    names: Vec<Rc<str>>,
+}
+
+impl PlayerNames {
+    pub fn new() -> PlayerNames {
+        PlayerNames::default()
+    }
+
+    /// Id of the player, handing out the next free one to new players
+    pub fn intern(&mut self, name: &str) -> u32 {
+        if let Some(id) = self.ids.get(name) {
+            return *id;
+        }
+        let id = self.names.len() as u32;
+        let name: Rc<str> = Rc::from(name);
+        self.ids.insert(name.clone(), id);
+        self.names.push(name);
+        id
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.names.iter().map(|name| &**name)
+    }
+}
+
+/// Mutable references to two different elements
+fn pair_mut<T>(items: &mut [T], first: usize, second: usize) -> (&mut T, &mut T) {
+    assert!(first != second);
+    if first < second {
+        let (head, tail) = items.split_at_mut(second);
+        (&mut head[first], &mut tail[0])
+    } else {
+        let (head, tail) = items.split_at_mut(first);
+        (&mut tail[0], &mut head[second])
+    }
+}
+
+/// First line of a snapshot, with the version of the format
+const SNAPSHOT_HEADER: &str = "rratings snapshot 2";
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Parameters as written to a snapshot, key=value,...
+fn settings_field(params: &Settings) -> String {
+    let params: Vec<_> = params
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect();
+    params.join(",")
+}
+
+/// The next line of a snapshot, split into its tab separated fields
+fn snapshot_fields<I: Iterator<Item = io::Result<String>>>(
+    lines: &mut I,
+) -> io::Result<Vec<String>> {
+    let line = lines
+        .next()
+        .ok_or_else(|| invalid("Snapshot is truncated"))??;
+    Ok(line.split('\t').map(String::from).collect())
+}
+
+/// A line with the label and a value, like a count
+fn snapshot_value<T: std::str::FromStr, I: Iterator<Item = io::Result<String>>>(
+    lines: &mut I,
+    label: &str,
+) -> io::Result<T> {
+    let fields = snapshot_fields(lines)?;
+    if fields.len() != 2 || fields[0] != label {
+        return Err(invalid(&format!("Expected {} in snapshot", label)));
+    }
+    parse_field(&fields[1])
+}
+
+fn parse_field<T: std::str::FromStr>(field: &str) -> io::Result<T> {
+    field
+        .parse()
+        .map_err(|_| invalid(&format!("Bad field {} in snapshot", field)))
+}
+
+/// Report file of one time control: ratings.txt becomes ratings-blitz.txt
+pub fn report_path(report_filename: &str, speed: &TimeControl) -> PathBuf {
+    let path = Path::new(report_filename);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, speed.name(), extension.to_string_lossy()),
+        None => format!("{}-{}", stem, speed.name()),
+    };
+    path.with_file_name(name)
+}
+
+/// The players of one time control. As on lichess, a player's ratings in
+/// different pools are independent.
+struct Pool {
+    names: PlayerNames,
+    // Indexed by player id
+    players: Vec<Player>,
     stats: Mutex<StatsDB>,
+    // Player ids by the id of the player in the cache being read, NO_ID
+    // for the ones not seen yet
+    cache_ids: Vec<u32>,
+    // White before the game, for simultaneous updates. Kept between games
+    // so the copy doesn't allocate.
+    white_before: Option<Player>,
 }
 
-impl RatingDB {
-    pub fn new() -> RatingDB {
-        RatingDB {
-            db: Mutex::new(MapType::new()),
-            stats: Mutex::new(StatsDB::new()),
+const NO_ID: u32 = u32::MAX;
+
+impl Pool {
+    fn new(system_count: usize, keep_games: bool, keep_strata: bool) -> Pool {
+        Pool {
+            names: PlayerNames::new(),
+            players: vec![],
+            stats: Mutex::new(StatsDB::new(system_count, keep_games, keep_strata)),
+            cache_ids: vec![],
+            white_before: None,
         }
     }
 
-    pub fn player_count(&self) -> usize {
-        self.db.lock().unwrap().len()
+    fn player_id(
+        &mut self,
+        name: &str,
+        cache_id: Option<u32>,
+        res_time: &DateTime<Utc>,
+        systems: &[SystemInstance],
+    ) -> usize {
+        let id = match cache_id {
+            Some(cache_id) => self.cached_id(name, cache_id),
+            None => self.names.intern(name),
+        } as usize;
+        if id == self.players.len() {
+            self.players.push(Player::new(res_time, systems));
+        }
+        id
+    }
+
+    /// Id of the player with the given id in the cache being read, which
+    /// only needs the name the first time
+    fn cached_id(&mut self, name: &str, cache_id: u32) -> u32 {
+        let slot = cache_id as usize;
+        if slot >= self.cache_ids.len() {
+            self.cache_ids.resize(slot + 1, NO_ID);
+        }
+        if self.cache_ids[slot] == NO_ID {
+            self.cache_ids[slot] = self.names.intern(name);
+        }
+        self.cache_ids[slot]
     }
 
-    pub fn update(&mut self, update: ResultUpdate) {
+    /// Rate the game, recording the predictions for white and or black.
+    /// Returns the ids of the players, if it was rated.
+    fn update(
+        &mut self,
+        update: &ResultUpdate,
+        systems: &[SystemInstance],
+        sides: (bool, bool),
+        order: UpdateOrder,
+    ) -> Option<(usize, usize)> {
         let result = update.result.unwrap();
         let res_time = update.datetime;
 
-        let mut db = self.db.lock().unwrap();
-
-        let mut white_entry = match db.get(&update.white) {
-            Some(entry) => entry.clone(),
-            None => Player::new(&res_time),
-        };
-        let mut black_entry = match db.get(&update.black) {
-            Some(entry) => entry.clone(),
-            None => Player::new(&res_time),
-        };
-
-        // Pass and update prediction stats for white only
-        white_entry.update_with_result(
-            Color::White,
-            &result,
+        let cache_ids = update.cache_ids;
+        let white = self.player_id(
+            &update.white,
+            cache_ids.map(|ids| ids.0),
             &res_time,
-            &black_entry,
-            Some(&self.stats),
-            Some(&update),
+            systems,
         );
-        black_entry.update_with_result(Color::Black, &result, &res_time, &white_entry, None, None);
+        let black = self.player_id(
+            &update.black,
+            cache_ids.map(|ids| ids.1),
+            &res_time,
+            systems,
+        );
+        // Can't happen on lichess
+        if white == black {
+            return None;
+        }
+        let (white_entry, black_entry) = pair_mut(&mut self.players, white, black);
 
-        db.insert(update.white, white_entry);
-        db.insert(update.black, black_entry);
+        {
+            let mut stats = self.stats.lock().unwrap();
+            stats.record_lichess(update);
+            if sides.0 {
+                white_entry.record_predictions(
+                    Color::White,
+                    &result,
+                    &res_time,
+                    black_entry,
+                    &mut stats,
+                );
+            }
+            if sides.1 {
+                black_entry.record_predictions(
+                    Color::Black,
+                    &result,
+                    &res_time,
+                    white_entry,
+                    &mut stats,
+                );
+            }
+            if sides.0 && sides.1 && stats.keep_games {
+                stats.merge_sides();
+            }
+        }
+
+        match order {
+            UpdateOrder::Simultaneous => {
+                match self.white_before {
+                    Some(ref mut white_before) => white_before.copy_from(white_entry),
+                    None => self.white_before = Some(white_entry.clone()),
+                }
+                let white_before = self.white_before.as_ref().unwrap();
+                white_entry.update_with_result(Color::White, &result, &res_time, black_entry);
+                black_entry.update_with_result(Color::Black, &result, &res_time, white_before);
+            }
+            UpdateOrder::Sequential => {
+                white_entry.update_with_result(Color::White, &result, &res_time, black_entry);
+                black_entry.update_with_result(Color::Black, &result, &res_time, white_entry);
+            }
+        }
+        Some((white, black))
     }
 
-    pub fn get_stats(&self) -> String {
-        let stats = self.stats.lock().unwrap();
+    fn dump_report(&self, report_path: &Path) {
+        let mut sort_db: Vec<_> = self.names.iter().zip(self.players.iter()).collect();
+        // Sort by lower confidence bound of the first rating system
+        sort_db.sort_by_key(|x| {
+            let rating = &x.1.ratings[0];
+            -((rating.rating() as i32) - 2 * (rating.deviation() as i32))
+        });
 
-        let pred_rate = 100.0 * stats.glicko_predicted as f64 / stats.glicko_guess as f64;
-        let mse = stats.glicko_mse_accum / stats.glicko_mse_total;
+        let mut file = File::create(report_path).unwrap();
 
-        let pred_rate_g2 = 100.0 * stats.glicko2_predicted as f64 / stats.glicko2_guess as f64;
-        let mse_g2 = stats.glicko2_mse_accum / stats.glicko2_mse_total;
+        for (key, val) in sort_db.iter() {
+            let mut player = key.to_string();
+            for rating in val.ratings.iter() {
+                player += &format!(",{}", rating);
+            }
+            player += "\n";
+            file.write_all(player.as_bytes()).unwrap();
+        }
+    }
+}
 
-        let pred_rate_l2 = 100.0 * stats.ligcko2_predicted as f64 / stats.ligcko2_guess as f64;
-        let mse_l2 = stats.ligcko2_mse_accum / stats.ligcko2_mse_total;
+pub struct RatingDB {
+    // One pool per time control seen so far
+    pools: BTreeMap<TimeControl, Pool>,
+    systems: Vec<SystemInstance>,
+    keep_games: bool,
+    keep_strata: bool,
+    perspective: Perspective,
+    order: UpdateOrder,
+    // Picks the side for Perspective::Random
+    rng: Rng,
+    history: Option<HistoryRecorder>,
+}
 
-        let lichess_pred_rate = 100.0 * stats.lichess_predicted as f64 / stats.lichess_guess as f64;
+impl RatingDB {
+    pub fn new(systems: Vec<SystemInstance>) -> RatingDB {
+        RatingDB {
+            pools: BTreeMap::new(),
+            systems,
+            keep_games: false,
+            keep_strata: false,
+            perspective: Perspective::White,
+            order: UpdateOrder::Simultaneous,
+            rng: Rng::new(0x5eed),
+            history: None,
+        }
+    }
 
-        let mut out = format!("{:.3}% G1 p-rate, {:.4} G1 MSE", pred_rate, mse);
-        out += &format!(", {:.3}% G2 p-rate, {:.4} G2 MSE", pred_rate_g2, mse_g2);
-        out += &format!(", {:.3}% L2 p-rate, {:.4} L2 MSE", pred_rate_l2, mse_l2);
-        out += &format!(", {:.3}% lichess p-rate ", lichess_pred_rate);
+    pub fn set_perspective(&mut self, perspective: Perspective) {
+        self.perspective = perspective;
+    }
 
-        out
+    pub fn set_update_order(&mut self, order: UpdateOrder) {
+        self.order = order;
+    }
+
+    pub fn systems(&self) -> &[SystemInstance] {
+        &self.systems
+    }
+
+    /// Record the ratings of the players after every game from now on
+    pub fn record_history(&mut self, recorder: HistoryRecorder) {
+        self.history = Some(recorder);
+    }
+
+    /// Write out the history recorded so far, if any
+    pub fn flush_history(&mut self) -> io::Result<()> {
+        match &mut self.history {
+            Some(history) => history.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Keep the loss of every game from now on, so get_stats can tell how
+    /// significant the differences between the systems are
+    pub fn keep_games(&mut self) {
+        self.keep_games = true;
+        for pool in self.pools.values_mut() {
+            pool.stats.lock().unwrap().keep_games = true;
+        }
+    }
+
+    /// Split the stats by the rating, RD, number of prior games and time
+    /// since the last game of the player and the rating difference, from
+    /// now on
+    pub fn keep_strata(&mut self) {
+        self.keep_strata = true;
+        for pool in self.pools.values_mut() {
+            pool.stats.lock().unwrap().keep_strata = true;
+        }
+    }
+
+    /// Players over all pools, counting a player once per pool
+    pub fn player_count(&self) -> usize {
+        self.pools.values().map(|pool| pool.players.len()).sum()
+    }
+
+    /// Forget the player ids of the cache read before
+    pub fn forget_cache_ids(&mut self) {
+        for pool in self.pools.values_mut() {
+            pool.cache_ids.clear();
+        }
+    }
+
+    pub fn update(&mut self, update: &ResultUpdate) {
+        let system_count = self.systems.len();
+        let keep_games = self.keep_games;
+        let keep_strata = self.keep_strata;
+        let sides = match self.perspective {
+            Perspective::White => (true, false),
+            Perspective::Black => (false, true),
+            Perspective::Both => (true, true),
+            Perspective::Random => {
+                let white = self.rng.below(2) == 0;
+                (white, !white)
+            }
+        };
+        let pool = self
+            .pools
+            .entry(update.speed.clone())
+            .or_insert_with(|| Pool::new(system_count, keep_games, keep_strata));
+        let ids = pool.update(update, &self.systems, sides, self.order);
+        if let (Some(history), Some((white, black))) = (&mut self.history, ids) {
+            history.record(update, &pool.players[white], &pool.players[black]);
+        }
+    }
+
+    /// Prediction stats, a line per pool and one more per system with the
+    /// log-loss and the Brier decomposition. With calibration, the
+    /// calibration table of every system follows. With a number of
+    /// bootstrap resamples, and the games kept, the confidence intervals of
+    /// every system and tests between every two systems follow.
+    pub fn get_stats(&self, calibration: bool, resamples: Option<usize>) -> String {
+        let mut lines = vec![];
+        for (speed, pool) in self.pools.iter() {
+            let stats = pool.stats.lock().unwrap();
+            if stats.systems.iter().all(|system| system.mse_total == 0.0) {
+                continue;
+            }
+
+            let mut out = format!("{}: ", speed.name());
+            for (system, system_stats) in self.systems.iter().zip(stats.systems.iter()) {
+                let pred_rate = 100.0 * system_stats.predicted as f64 / system_stats.guess as f64;
+                let mse = system_stats.mse();
+                out += &format!(
+                    "{:.3}% {} p-rate, {:.4} {} MSE, ",
+                    pred_rate, system.name, mse, system.name
+                );
+            }
+
+            if stats.lichess_guess > 0 {
+                let lichess_pred_rate =
+                    100.0 * stats.lichess_predicted as f64 / stats.lichess_guess as f64;
+                out += &format!("{:.3}% lichess p-rate ", lichess_pred_rate);
+            }
+            lines.push(out);
+
+            for (i, (system, system_stats)) in
+                self.systems.iter().zip(stats.systems.iter()).enumerate()
+            {
+                let (reliability, resolution, uncertainty) = system_stats.brier_decomposition();
+                lines.push(format!(
+                    "{} {}: {:.4} log-loss, {:.4} Brier = {:.4} REL - {:.4} RES + {:.4} UNC",
+                    speed.name(),
+                    system.name,
+                    system_stats.log_loss(),
+                    system_stats.mse(),
+                    reliability,
+                    resolution,
+                    uncertainty
+                ));
+                let draw_model = &stats.draw_models[i];
+                lines.push(format!(
+                    "{} {}: {:.4} W/D/L log-loss, {:.3}% W/D/L p-rate, {:.2}% draws predicted, \
+                     {:.2}% observed, equal players draw {:.2}% at 1200, {:.2}% at 1800, \
+                     {:.2}% at 2400",
+                    speed.name(),
+                    system.name,
+                    system_stats.log_loss3(),
+                    100.0 * system_stats.predicted3 as f64 / system_stats.mse_total,
+                    100.0 * system_stats.draw_expected_accum / system_stats.mse_total,
+                    100.0 * system_stats.draws as f64 / system_stats.mse_total,
+                    100.0 * draw_model.draw_rate(1200.0),
+                    100.0 * draw_model.draw_rate(1800.0),
+                    100.0 * draw_model.draw_rate(2400.0)
+                ));
+                let mut out = format!("{} {}:", speed.name(), system.name);
+                for (side, name) in system_stats.sides.iter().zip(["white", "black"].iter()) {
+                    if side.games > 0 {
+                        out += &format!(
+                            " as {} {:.3}% p-rate, {:.4} MSE, {:.4} log-loss;",
+                            name,
+                            100.0 * side.p_rate(),
+                            side.mse(),
+                            side.log_loss()
+                        );
+                    }
+                }
+                lines.push(out.trim_end_matches(';').to_string());
+                if calibration {
+                    lines.push(system_stats.calibration_table().trim_end().to_string());
+                }
+            }
+
+            // Only from games with the lichess ratings of both players
+            if stats.lichess_guess > 0 {
+                let mut out = format!("{}: draws by lichess rating:", speed.name());
+                for (band, (games, draws)) in stats.draw_bands.iter() {
+                    out += &format!(" {} {:.1}%", band, 100.0 * *draws as f64 / *games as f64);
+                }
+                lines.push(out);
+
+                let advantage = &stats.white_advantage;
+                lines.push(format!(
+                    "{}: white advantage {:+.1} Elo, white scores {:.2}% over {} games",
+                    speed.name(),
+                    advantage.elo,
+                    100.0 * advantage.white_score(),
+                    advantage.games
+                ));
+            }
+
+            if stats.systems.iter().any(|system| !system.strata.is_empty()) {
+                let names: Vec<_> = self.systems.iter().map(|system| &system.name[..]).collect();
+                let system_strata: Vec<_> =
+                    stats.systems.iter().map(|system| &system.strata).collect();
+                lines.extend(strata::tables(speed.name(), &names, &system_strata));
+            }
+
+            if let Some(resamples) = resamples {
+                lines.extend(self.significance(speed, &stats, resamples));
+            }
+
+            // How much better than Elo
+            if let Some(base) = self.systems.iter().position(|system| system.baseline) {
+                let base_mse = stats.systems[base].mse();
+                let mut out = format!("{}: MSE vs {}:", speed.name(), self.systems[base].name);
+                for (i, (system, system_stats)) in
+                    self.systems.iter().zip(stats.systems.iter()).enumerate()
+                {
+                    if i == base {
+                        continue;
+                    }
+                    let change = 100.0 * (system_stats.mse() - base_mse) / base_mse;
+                    out += &format!(" {} {:+.2}%", system.name, change);
+                }
+                lines.push(out);
+            }
+        }
+
+        lines.join("\n")
+    }
+
+    fn significance(&self, speed: &TimeControl, stats: &StatsDB, resamples: usize) -> Vec<String> {
+        let mut lines = vec![];
+        for (system, system_stats) in self.systems.iter().zip(stats.systems.iter()) {
+            let intervals = match bootstrap(&system_stats.games, resamples) {
+                Some(intervals) => intervals,
+                None => continue,
+            };
+            lines.push(format!(
+                "{} {}: 95% intervals {:.3}-{:.3}% p-rate, {:.4}-{:.4} MSE, {:.4}-{:.4} log-loss",
+                speed.name(),
+                system.name,
+                100.0 * intervals.p_rate.0,
+                100.0 * intervals.p_rate.1,
+                intervals.mse.0,
+                intervals.mse.1,
+                intervals.log_loss.0,
+                intervals.log_loss.1
+            ));
+        }
+
+        // Diebold-Mariano for every pair
+        for (i, first) in stats.systems.iter().enumerate() {
+            if first.games.is_empty() {
+                continue;
+            }
+            for (j, second) in stats.systems.iter().enumerate().skip(i + 1) {
+                let pairs = || first.games.iter().zip(second.games.iter());
+                let mse: Vec<_> = pairs()
+                    .map(|(a, b)| f64::from(a.squared_error) - f64::from(b.squared_error))
+                    .collect();
+                let log_loss: Vec<_> = pairs()
+                    .map(|(a, b)| f64::from(a.log_loss) - f64::from(b.log_loss))
+                    .collect();
+                let (mse_diff, mse_dm, mse_p) = diebold_mariano(&mse);
+                let (log_loss_diff, log_loss_dm, log_loss_p) = diebold_mariano(&log_loss);
+                lines.push(format!(
+                    "{} {} - {}: {:+.5} MSE (DM {:.2}, p {:.4}), {:+.5} log-loss (DM {:.2}, p {:.4})",
+                    speed.name(),
+                    self.systems[i].name,
+                    self.systems[j].name,
+                    mse_diff,
+                    mse_dm,
+                    mse_p,
+                    log_loss_diff,
+                    log_loss_dm,
+                    log_loss_p
+                ));
+            }
+        }
+        lines
+    }
+
+    /// Prediction stats of a system over all pools
+    pub fn system_stats(&self, index: usize) -> SystemStats {
+        let mut total = SystemStats::default();
+        for pool in self.pools.values() {
+            total.merge(&pool.stats.lock().unwrap().systems[index]);
+        }
+        total
     }
 
     pub fn stats_reset(&mut self) {
-        self.stats = Mutex::new(StatsDB::new());
+        for pool in self.pools.values_mut() {
+            let mut stats = StatsDB::new(self.systems.len(), self.keep_games, self.keep_strata);
+            {
+                let mut old_stats = pool.stats.lock().unwrap();
+                stats.draw_models = mem::take(&mut old_stats.draw_models);
+                stats.white_advantage = mem::take(&mut old_stats.white_advantage);
+            }
+            pool.stats = Mutex::new(stats);
+        }
     }
 
+    /// Write everything needed to carry on rating later: the systems and
+    /// their parameters, the state of the random perspective, and per pool
+    /// every player's ratings, last game and number of games, and the draw
+    /// models and white advantage learned so far. The prediction stats
+    /// themselves are not saved, so save after stats_reset.
+    pub fn save_snapshot(&self, path: &Path) -> io::Result<()> {
+        // Names are fields of tab separated lines
+        for pool in self.pools.values() {
+            if let Some(name) = pool
+                .names
+                .iter()
+                .find(|name| name.contains(&['\t', '\n', '\r'][..]))
+            {
+                return Err(invalid(&format!(
+                    "Player name {:?} can't be saved in a snapshot",
+                    name
+                )));
+            }
+        }
+        // Don't leave a half written snapshot if we are interrupted
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut file = BufWriter::new(File::create(&tmp_path)?);
+            writeln!(file, "{}", SNAPSHOT_HEADER)?;
+            writeln!(file, "systems\t{}", self.systems.len())?;
+            for system in self.systems.iter() {
+                writeln!(file, "{}\t{}", system.name, settings_field(&system.params))?;
+            }
+            writeln!(file, "rng\t{}", self.rng.state())?;
+            writeln!(file, "pools\t{}", self.pools.len())?;
+            for (speed, pool) in self.pools.iter() {
+                let stats = pool.stats.lock().unwrap();
+                writeln!(file, "{}\t{}", speed.name(), pool.players.len())?;
+                let advantage = &stats.white_advantage;
+                writeln!(
+                    file,
+                    "{}\t{}\t{}",
+                    advantage.elo, advantage.games, advantage.white_score_accum
+                )?;
+                for draw_model in stats.draw_models.iter() {
+                    writeln!(file, "{}\t{}", draw_model.a, draw_model.b)?;
+                }
+                for (name, player) in pool.names.iter().zip(pool.players.iter()) {
+                    write!(
+                        file,
+                        "{}\t{}\t{}",
+                        name,
+                        player.mtime.timestamp(),
+                        player.games
+                    )?;
+                    for rating in player.ratings.iter() {
+                        write!(file, "\t{}", rating.snapshot())?;
+                    }
+                    writeln!(file)?;
+                }
+            }
+            file.flush()?;
+        }
+        fs::rename(&tmp_path, path)
+    }
+
+    /// Read a snapshot written by save_snapshot. The systems must be the
+    /// same ones, with the same parameters.
+    pub fn load_snapshot(path: &Path, systems: Vec<SystemInstance>) -> io::Result<RatingDB> {
+        let mut lines = BufReader::new(File::open(path)?).lines();
+        let header = lines.next().ok_or_else(|| invalid("Snapshot is empty"))??;
+        if header != SNAPSHOT_HEADER {
+            return Err(invalid(&format!("{} is not a snapshot", path.display())));
+        }
+
+        let system_count: usize = snapshot_value(&mut lines, "systems")?;
+        let mut saved_systems = vec![];
+        for _ in 0..system_count {
+            saved_systems.push(snapshot_fields(&mut lines)?.join(" "));
+        }
+        let wanted_systems: Vec<_> = systems
+            .iter()
+            .map(|system| format!("{} {}", system.name, settings_field(&system.params)))
+            .collect();
+        if saved_systems != wanted_systems {
+            return Err(invalid(&format!(
+                "Snapshot has the systems {}, not {}",
+                saved_systems.join("; "),
+                wanted_systems.join("; ")
+            )));
+        }
+
+        let mut db = RatingDB::new(systems);
+        db.rng = Rng::new(snapshot_value(&mut lines, "rng")?);
+        let pool_count: usize = snapshot_value(&mut lines, "pools")?;
+        for _ in 0..pool_count {
+            let fields = snapshot_fields(&mut lines)?;
+            let speed = TimeControl::from_name(&fields[0])
+                .ok_or_else(|| invalid(&format!("Unknown time control {}", fields[0])))?;
+            let player_count: usize = parse_field(fields.get(1).map_or("", |count| &count[..]))?;
+            let mut pool = Pool::new(system_count, false, false);
+            {
+                let stats = pool.stats.get_mut().unwrap();
+                let fields = snapshot_fields(&mut lines)?;
+                if fields.len() != 3 {
+                    return Err(invalid("Bad white advantage in snapshot"));
+                }
+                stats.white_advantage = WhiteAdvantage {
+                    elo: parse_field(&fields[0])?,
+                    games: parse_field(&fields[1])?,
+                    white_score_accum: parse_field(&fields[2])?,
+                };
+                for draw_model in stats.draw_models.iter_mut() {
+                    let fields = snapshot_fields(&mut lines)?;
+                    if fields.len() != 2 {
+                        return Err(invalid("Bad draw model in snapshot"));
+                    }
+                    draw_model.a = parse_field(&fields[0])?;
+                    draw_model.b = parse_field(&fields[1])?;
+                }
+            }
+            for _ in 0..player_count {
+                let fields = snapshot_fields(&mut lines)?;
+                if fields.len() != 3 + system_count {
+                    return Err(invalid(&format!("Bad player {} in snapshot", fields[0])));
+                }
+                let mtime = Utc.timestamp(parse_field(&fields[1])?, 0);
+                let mut player = Player::new(&mtime, &db.systems);
+                player.games = parse_field(&fields[2])?;
+                for (rating, state) in player.ratings.iter_mut().zip(fields[3..].iter()) {
+                    rating
+                        .restore(state)
+                        .map_err(|e| invalid(&format!("Player {}: {}", fields[0], e)))?;
+                }
+                pool.names.intern(&fields[0]);
+                pool.players.push(player);
+            }
+            db.pools.insert(speed, pool);
+        }
+        Ok(db)
+    }
+
+    /// Write a report per pool, see report_path
     pub fn dump_report(self, report_filename: &str) {
-        let mut db = self.db.lock().unwrap();
-        let mut sort_db: Vec<_> = db.drain().collect();
-        // Sort by lower confidence bound of Glicko-1 rating
-        sort_db.sort_by_key(|x| -((x.1.g1rating.r as i32) - 2 * (x.1.g1rating.rd as i32)));
+        for (speed, pool) in self.pools.iter() {
+            pool.dump_report(&report_path(report_filename, speed));
+        }
+    }
+}
 
-        let mut file = File::create(report_filename).unwrap();
+#[cfg(test)]
+mod tests {
+    use pgn_reader::{Color, Outcome};
 
-        for (key, val) in sort_db.iter() {
-            let player = format!(
-                "{},{},{},{}\n",
-                key, val.g1rating, val.g2rating, val.l2rating,
+    use super::{
+        most_likely, Perspective, Player, PlayerNames, RatingDB, SystemStats, UpdateOrder,
+    };
+    use ratingsystem::{lookup, Settings, SYSTEMS};
+    use testutil::{game, temp_path};
+    use TimeControl;
+
+    const DAY: i64 = 24 * 60 * 60;
+
+    fn new_db(system: &str, order: UpdateOrder) -> RatingDB {
+        let spec = lookup(system).unwrap();
+        let mut db = RatingDB::new(vec![spec.instantiate(system, &Settings::new()).unwrap()]);
+        db.set_update_order(order);
+        db
+    }
+
+    fn white_wins() -> Outcome {
+        Outcome::Decisive {
+            winner: Color::White,
+        }
+    }
+
+    fn black_wins() -> Outcome {
+        Outcome::Decisive {
+            winner: Color::Black,
+        }
+    }
+
+    fn player(db: &RatingDB, name: &str) -> Player {
+        let pool = &db.pools[&TimeControl::Blitz];
+        let id = pool.names.iter().position(|known| known == name).unwrap();
+        pool.players[id].clone()
+    }
+
+    fn rating(db: &RatingDB, name: &str) -> f32 {
+        player(db, name).ratings[0].rating()
+    }
+
+    // Gives a and b different ratings, RDs and times of their last game
+    fn history(db: &mut RatingDB) {
+        db.update(&game("a", "c", white_wins(), 0));
+        db.update(&game("d", "a", Outcome::Draw, DAY));
+        db.update(&game("b", "e", black_wins(), 3 * DAY));
+    }
+
+    /// Run the check on a database of every known system, after history
+    fn for_every_system<F: FnMut(&str, RatingDB)>(mut check: F) {
+        for spec in SYSTEMS.iter() {
+            let mut db = new_db(spec.name, UpdateOrder::Simultaneous);
+            history(&mut db);
+            check(spec.name, db);
+        }
+    }
+
+    #[test]
+    fn simultaneous_elo_is_zero_sum() {
+        let mut db = new_db("E", UpdateOrder::Simultaneous);
+        db.update(&game("a", "b", white_wins(), 0));
+        assert!((rating(&db, "a") - 1510.0).abs() < 1e-3);
+        assert!((rating(&db, "b") - 1490.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn sequential_black_plays_the_new_white() {
+        let mut db = new_db("E", UpdateOrder::Sequential);
+        db.update(&game("a", "b", white_wins(), 0));
+        assert!((rating(&db, "a") - 1510.0).abs() < 1e-3);
+        // Against a 1510 player black expected less, and loses less
+        let expected = 1.0 / (1.0 + 10.0f32.powf(10.0 / 400.0));
+        assert!((rating(&db, "b") - (1500.0 - 20.0 * expected)).abs() < 1e-3);
+        assert!((rating(&db, "a") + rating(&db, "b") - 3000.0).abs() > 0.1);
+    }
+
+    #[test]
+    fn black_is_updated_from_white_before_the_game() {
+        for_every_system(|system, mut db| {
+            let white_before = player(&db, "a");
+            let mut black = player(&db, "b");
+
+            let update = game("a", "b", black_wins(), 10 * DAY);
+            db.update(&update);
+            black.update_with_result(Color::Black, &black_wins(), &update.datetime, &white_before);
+            assert_eq!(
+                rating(&db, "b"),
+                black.ratings[0].rating(),
+                "{} used white's new rating",
+                system
             );
-            file.write_all(player.as_bytes()).unwrap();
+        });
+    }
+
+    #[test]
+    fn sequential_black_is_updated_from_white_after_the_game() {
+        let mut db = new_db("G1", UpdateOrder::Sequential);
+        history(&mut db);
+        let mut black = player(&db, "b");
+
+        let update = game("a", "b", black_wins(), 10 * DAY);
+        db.update(&update);
+        let white_after = player(&db, "a");
+        black.update_with_result(Color::Black, &black_wins(), &update.datetime, &white_after);
+        assert_eq!(rating(&db, "b"), black.ratings[0].rating());
+    }
+
+    // Without a white advantage, who had white mustn't matter
+    fn colors_swapped(order: UpdateOrder) -> (f32, f32) {
+        let mut white_a = new_db("G1", order);
+        history(&mut white_a);
+        white_a.update(&game("a", "b", white_wins(), 10 * DAY));
+
+        let mut black_a = new_db("G1", order);
+        history(&mut black_a);
+        black_a.update(&game("b", "a", black_wins(), 10 * DAY));
+
+        (
+            rating(&white_a, "b") - rating(&black_a, "b"),
+            rating(&white_a, "a") - rating(&black_a, "a"),
+        )
+    }
+
+    #[test]
+    fn simultaneous_does_not_depend_on_colors() {
+        assert_eq!(colors_swapped(UpdateOrder::Simultaneous), (0.0, 0.0));
+    }
+
+    #[test]
+    fn sequential_depends_on_colors() {
+        assert!(colors_swapped(UpdateOrder::Sequential) != (0.0, 0.0));
+    }
+
+    #[test]
+    fn snapshot_restores_every_system() {
+        let path = temp_path("snapshot.txt");
+        for_every_system(|system, mut db| {
+            db.save_snapshot(&path).unwrap();
+            let spec = lookup(system).unwrap();
+            let systems = vec![spec.instantiate(system, &Settings::new()).unwrap()];
+            let mut resumed = RatingDB::load_snapshot(&path, systems).unwrap();
+
+            db.update(&game("a", "b", white_wins(), 3 * DAY + 60));
+            resumed.update(&game("a", "b", white_wins(), 3 * DAY + 60));
+            for name in ["a", "b", "c", "d", "e"].iter() {
+                let (before, after) = (player(&db, name), player(&resumed, name));
+                assert_eq!(before.mtime, after.mtime, "{}", system);
+                assert_eq!(before.games, after.games, "{}", system);
+                assert_eq!(
+                    before.ratings[0].snapshot(),
+                    after.ratings[0].snapshot(),
+                    "{}",
+                    system
+                );
+            }
+        });
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn snapshot_keeps_the_random_perspective() {
+        let path = temp_path("rng.txt");
+        let mut db = new_db("E", UpdateOrder::Simultaneous);
+        db.set_perspective(Perspective::Random);
+        history(&mut db);
+        db.save_snapshot(&path).unwrap();
+        let spec = lookup("E").unwrap();
+        let systems = vec![spec.instantiate("E", &Settings::new()).unwrap()];
+        let resumed = RatingDB::load_snapshot(&path, systems).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(resumed.rng.state(), db.rng.state());
+    }
+
+    #[test]
+    fn snapshot_rejects_names_it_cannot_hold() {
+        let path = temp_path("names.txt");
+        for name in ["a\tb", "a\nb"].iter() {
+            let mut db = new_db("E", UpdateOrder::Simultaneous);
+            db.update(&game(name, "c", white_wins(), 0));
+            assert!(db.save_snapshot(&path).is_err());
+            assert!(!path.exists());
         }
     }
+
+    #[test]
+    fn copy_from_copies_every_system() {
+        for_every_system(|system, db| {
+            let (a, mut b) = (player(&db, "a"), player(&db, "b"));
+            b.copy_from(&a);
+            assert_eq!(b.mtime, a.mtime, "{}", system);
+            assert_eq!(b.games, a.games, "{}", system);
+            assert_eq!(
+                b.ratings[0].snapshot(),
+                a.ratings[0].snapshot(),
+                "{}",
+                system
+            );
+        });
+    }
+
+    #[test]
+    fn names_get_dense_ids() {
+        let mut names = PlayerNames::new();
+        let ids: Vec<_> = ["a", "b", "a", "c", "b"]
+            .iter()
+            .map(|name| names.intern(name))
+            .collect();
+        assert_eq!(ids, vec![0, 1, 0, 2, 1]);
+        assert_eq!(names.len(), 3);
+        assert_eq!(names.iter().collect::<Vec<_>>(), vec!["a", "b", "c"]);
+    }
+
+    // Every bucket holds one expected score, so the split is exact
+    fn calibration_games() -> SystemStats {
+        let mut stats = SystemStats::default();
+        for (score, expected_score) in [
+            (1.0, 0.8),
+            (1.0, 0.8),
+            (0.5, 0.8),
+            (0.0, 0.8),
+            (0.5, 0.5),
+            (1.0, 0.5),
+            (0.0, 0.3),
+            (0.0, 0.3),
+            (1.0, 0.3),
+        ]
+        .iter()
+        {
+            stats.record(*score, *expected_score);
+        }
+        stats
+    }
+
+    #[test]
+    fn brier_decomposition_adds_up_to_the_mse() {
+        let stats = calibration_games();
+        let (reliability, resolution, uncertainty) = stats.brier_decomposition();
+        assert!(reliability > 0.0 && resolution > 0.0);
+        let brier = reliability - resolution + uncertainty;
+        assert!(
+            (brier - stats.mse()).abs() < 1e-6,
+            "{} vs {}",
+            brier,
+            stats.mse()
+        );
+    }
+
+    #[test]
+    fn calibration_bins_average_expected_and_observed_scores() {
+        let stats = calibration_games();
+        let bins: Vec<_> = stats
+            .bins
+            .iter()
+            .enumerate()
+            .filter(|(_, bin)| bin.games > 0)
+            .map(|(i, bin)| (i, bin.games, bin.score_accum / bin.games as f64))
+            .collect();
+        assert_eq!(bins, vec![(6, 3, 1.0 / 3.0), (10, 2, 0.75), (16, 4, 0.625)]);
+        assert!(stats
+            .calibration_table()
+            .contains("expected  30.00%, observed  33.33%"));
+    }
+
+    // Games and MSE scored for white and for black
+    fn side_stats(perspective: Perspective) -> [(u64, f64); 2] {
+        let mut db = new_db("E", UpdateOrder::Simultaneous);
+        db.set_perspective(perspective);
+        history(&mut db);
+        db.update(&game("a", "b", black_wins(), 10 * DAY));
+        let stats = db.pools[&TimeControl::Blitz].stats.lock().unwrap();
+        let sides = &stats.systems[0].sides;
+        [
+            (sides[0].games, sides[0].mse()),
+            (sides[1].games, sides[1].mse()),
+        ]
+    }
+
+    #[test]
+    fn predictions_are_split_by_side() {
+        let white = side_stats(Perspective::White);
+        assert_eq!((white[0].0, white[1].0), (4, 0));
+        let black = side_stats(Perspective::Black);
+        assert_eq!((black[0].0, black[1].0), (0, 4));
+        let random = side_stats(Perspective::Random);
+        assert_eq!(random[0].0 + random[1].0, 4);
+
+        // Elo's expected scores add up to one, so both sides score the same
+        let both = side_stats(Perspective::Both);
+        assert_eq!(both[0].0, 4);
+        assert_eq!(both[0], both[1]);
+        assert_eq!(both[0], white[0]);
+    }
+
+    #[test]
+    fn undefined_probabilities_predict_nothing() {
+        assert_eq!(most_likely(&[0.2, 0.5, 0.3]), Some(1));
+        assert_eq!(most_likely(&[0.2, f64::NAN, 0.3]), None);
+        assert_eq!(most_likely(&[f64::NAN; 3]), None);
+    }
+
+    #[test]
+    fn significance_tests_get_a_row_per_game() {
+        let mut db = new_db("G1", UpdateOrder::Simultaneous);
+        db.set_perspective(Perspective::Both);
+        db.keep_games();
+        history(&mut db);
+        db.update(&game("a", "b", black_wins(), 10 * DAY));
+        let stats = db.pools[&TimeControl::Blitz].stats.lock().unwrap();
+        let system = &stats.systems[0];
+        assert_eq!(system.games.len(), 4);
+        assert_eq!(system.sides[0].games + system.sides[1].games, 8);
+        let squared_error: f64 = system
+            .games
+            .iter()
+            .map(|g| f64::from(g.squared_error))
+            .sum();
+        let sides = system.sides[0].squared_error + system.sides[1].squared_error;
+        assert!((squared_error - sides / 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn cache_ids_are_forgotten_with_the_cache() {
+        let mut db = new_db("E", UpdateOrder::Simultaneous);
+        let mut first = game("a", "b", white_wins(), 0);
+        first.cache_ids = Some((0, 1));
+        db.update(&first);
+        // The next cache numbers its players anew
+        db.forget_cache_ids();
+        let mut second = game("b", "c", white_wins(), DAY);
+        second.cache_ids = Some((0, 1));
+        db.update(&second);
+        assert_eq!(db.player_count(), 3);
+        assert!((rating(&db, "a") - 1510.0).abs() < 1e-3);
+        assert!(rating(&db, "b") > 1490.0);
+        assert!(rating(&db, "c") < 1500.0);
+    }
 }
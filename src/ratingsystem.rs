@@ -0,0 +1,336 @@
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::{FromStr, Split};
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use pgn_reader::Color;
+
+use elo::{EloRating, FideK, FixedK, UscfK};
+use glicko::GlickoRating;
+use glicko2::Glicko2Rating;
+use glicko2batch::Glicko2BatchRating;
+use lichess::LichessGlicko2Rating;
+use ligcko2::Ligcko2Rating;
+use trueskill::TrueSkillRating;
+
+/// A per-player rating under some rating system. Every player carries one
+/// of these for each system the RatingDB was created with, and is only ever
+/// compared against the opponent's rating from the same system.
+pub trait RatingSystem: fmt::Display + fmt::Debug + Send {
+    /// Point estimate of the player's strength, on the usual 1500-centered scale
+    fn rating(&self) -> f32;
+
+    /// Uncertainty of the rating, on the same scale
+    fn deviation(&self) -> f32;
+
+    /// The rating as lichess shows it, truncated
+    fn int_rating(&self) -> i32 {
+        self.rating() as i32
+    }
+
+    /// Expected score against the opponent, given the player's color and
+    /// when both last played
+    fn expect(
+        &self,
+        color: Color,
+        old_time: &DateTime<Utc>,
+        result_time: &DateTime<Utc>,
+        opponent: &dyn RatingSystem,
+        opponent_time: &DateTime<Utc>,
+    ) -> f32;
+
+    /// Win, draw and loss probabilities against the opponent, for systems
+    /// that model draws themselves. The others leave it to the draw model
+    /// of the stats.
+    fn expect_outcomes(
+        &self,
+        _color: Color,
+        _old_time: &DateTime<Utc>,
+        _result_time: &DateTime<Utc>,
+        _opponent: &dyn RatingSystem,
+        _opponent_time: &DateTime<Utc>,
+    ) -> Option<[f32; 3]> {
+        None
+    }
+
+    fn update_with_result(
+        &mut self,
+        color: Color,
+        score: f32,
+        old_time: &DateTime<Utc>,
+        result_time: &DateTime<Utc>,
+        opponent: &dyn RatingSystem,
+        opponent_time: &DateTime<Utc>,
+    );
+
+    /// Full precision, comma separated state of the current rating, which
+    /// is all a snapshot needs for most systems
+    fn serialize(&self) -> String;
+
+    /// All of the state, for a snapshot of the RatingDB. The same as
+    /// serialize, unless the rating depends on more, like the games of a
+    /// pending rating period.
+    fn snapshot(&self) -> String {
+        self.serialize()
+    }
+
+    /// Set the state from what snapshot returned
+    fn restore(&mut self, state: &str) -> Result<(), String>;
+
+    fn box_clone(&self) -> Box<dyn RatingSystem>;
+
+    /// Make this a copy of another rating of the same system, without
+    /// allocating like box_clone
+    fn copy_from(&mut self, other: &dyn RatingSystem);
+
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl Clone for Box<dyn RatingSystem> {
+    fn clone(&self) -> Box<dyn RatingSystem> {
+        self.box_clone()
+    }
+}
+
+/// Recover the concrete rating of the opponent. Players always hold their
+/// systems in the same order, so a mismatch here is a bug.
+pub fn downcast<T: 'static>(opponent: &dyn RatingSystem) -> &T {
+    opponent
+        .as_any()
+        .downcast_ref::<T>()
+        .expect("Rating systems of players do not match")
+}
+
+/// Reads the comma separated fields of a snapshot one by one
+pub struct StateFields<'a> {
+    fields: Split<'a, char>,
+}
+
+impl<'a> StateFields<'a> {
+    pub fn new(state: &'a str) -> StateFields<'a> {
+        StateFields {
+            fields: state.split(','),
+        }
+    }
+
+    pub fn next<T: FromStr>(&mut self) -> Result<T, String> {
+        let field = self.fields.next().ok_or("Too few fields in rating state")?;
+        field
+            .parse()
+            .map_err(|_| format!("Bad field {} in rating state", field))
+    }
+
+    /// Fails if there are fields left
+    pub fn end(mut self) -> Result<(), String> {
+        match self.fields.next() {
+            Some(_) => Err("Too many fields in rating state".to_string()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Rating points to add to the player's side for playing the given color,
+/// so white_advantage is the difference between white and black
+pub fn color_bonus(color: Color, white_advantage: f32) -> f32 {
+    match color {
+        Color::White => white_advantage,
+        Color::Black => -white_advantage,
+    }
+}
+
+/// Tunable parameters of a rating system. They are set by name, so they
+/// can come from a config file or the command line alike.
+pub trait Parameters: Default + fmt::Debug + Send + Sync + 'static {
+    /// Set one parameter, failing for unknown keys and values out of range
+    fn set(&mut self, key: &str, value: f32) -> Result<(), String>;
+
+    /// Current value of every parameter
+    fn values(&self) -> Settings;
+
+    /// Check the parameters against each other, once all are set
+    fn check(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// The value of a scale, tau, period or RD, which must be above zero
+pub fn positive(key: &str, value: f32) -> Result<f32, String> {
+    if value > 0.0 {
+        Ok(value)
+    } else {
+        Err(format!("{} must be positive, not {}", key, value))
+    }
+}
+
+/// The value of a K-factor, count or variance, which can be zero
+pub fn non_negative(key: &str, value: f32) -> Result<f32, String> {
+    if value >= 0.0 {
+        Ok(value)
+    } else {
+        Err(format!("{} must not be negative, not {}", key, value))
+    }
+}
+
+pub type Settings = BTreeMap<String, f32>;
+
+type RatingFactory = Box<dyn Fn() -> Box<dyn RatingSystem> + Send + Sync>;
+
+/// A rating system together with one particular set of parameters.
+/// Several instances of the same system can be run side by side.
+pub struct SystemInstance {
+    /// Short label used in the stats output
+    pub name: String,
+    /// All parameters, including the defaulted ones
+    pub params: Settings,
+    /// Whether the other systems are compared against this one
+    pub baseline: bool,
+    factory: RatingFactory,
+}
+
+impl SystemInstance {
+    pub fn new_rating(&self) -> Box<dyn RatingSystem> {
+        (self.factory)()
+    }
+}
+
+pub struct SystemSpec {
+    pub name: &'static str,
+    /// Plain Elo, the reference everything else must beat
+    baseline: bool,
+    /// Run unless other systems are selected
+    pub default: bool,
+    create: fn(&Settings) -> Result<(Settings, RatingFactory), String>,
+}
+
+impl SystemSpec {
+    pub fn instantiate(&self, name: &str, settings: &Settings) -> Result<SystemInstance, String> {
+        let (params, factory) = (self.create)(settings)?;
+        Ok(SystemInstance {
+            name: name.to_string(),
+            params,
+            baseline: self.baseline,
+            factory,
+        })
+    }
+}
+
+fn configure<P, R>(
+    settings: &Settings,
+    new: fn(Arc<P>) -> R,
+) -> Result<(Settings, RatingFactory), String>
+where
+    P: Parameters,
+    R: RatingSystem + 'static,
+{
+    let mut params = P::default();
+    for (key, value) in settings.iter() {
+        if !value.is_finite() {
+            return Err(format!("{} must be a finite number, not {}", key, value));
+        }
+        params.set(key, *value)?;
+    }
+    params.check()?;
+    let values = params.values();
+    let params = Arc::new(params);
+    Ok((values, Box::new(move || Box::new(new(params.clone())))))
+}
+
+fn new_glicko(settings: &Settings) -> Result<(Settings, RatingFactory), String> {
+    configure(settings, GlickoRating::new)
+}
+
+fn new_glicko2(settings: &Settings) -> Result<(Settings, RatingFactory), String> {
+    configure(settings, Glicko2Rating::new)
+}
+
+fn new_glicko2_batch(settings: &Settings) -> Result<(Settings, RatingFactory), String> {
+    configure(settings, Glicko2BatchRating::new)
+}
+
+fn new_ligcko2(settings: &Settings) -> Result<(Settings, RatingFactory), String> {
+    configure(settings, Ligcko2Rating::new)
+}
+
+fn new_lichess_glicko2(settings: &Settings) -> Result<(Settings, RatingFactory), String> {
+    configure(settings, LichessGlicko2Rating::new)
+}
+
+fn new_trueskill(settings: &Settings) -> Result<(Settings, RatingFactory), String> {
+    configure(settings, TrueSkillRating::new)
+}
+
+fn new_elo(settings: &Settings) -> Result<(Settings, RatingFactory), String> {
+    configure(settings, EloRating::<FixedK>::new)
+}
+
+fn new_elo_fide(settings: &Settings) -> Result<(Settings, RatingFactory), String> {
+    configure(settings, EloRating::<FideK>::new)
+}
+
+fn new_elo_uscf(settings: &Settings) -> Result<(Settings, RatingFactory), String> {
+    configure(settings, EloRating::<UscfK>::new)
+}
+
+/// All known rating systems. New systems only need an entry here.
+pub const SYSTEMS: &[SystemSpec] = &[
+    SystemSpec {
+        name: "G1",
+        baseline: false,
+        default: true,
+        create: new_glicko,
+    },
+    SystemSpec {
+        name: "G2",
+        baseline: false,
+        default: true,
+        create: new_glicko2,
+    },
+    SystemSpec {
+        name: "G2B",
+        baseline: false,
+        default: false,
+        create: new_glicko2_batch,
+    },
+    SystemSpec {
+        name: "L2",
+        baseline: false,
+        default: true,
+        create: new_ligcko2,
+    },
+    SystemSpec {
+        name: "LG",
+        baseline: false,
+        default: false,
+        create: new_lichess_glicko2,
+    },
+    SystemSpec {
+        name: "TS",
+        baseline: false,
+        default: false,
+        create: new_trueskill,
+    },
+    SystemSpec {
+        name: "E",
+        baseline: true,
+        default: true,
+        create: new_elo,
+    },
+    SystemSpec {
+        name: "EF",
+        baseline: true,
+        default: false,
+        create: new_elo_fide,
+    },
+    SystemSpec {
+        name: "EU",
+        baseline: true,
+        default: false,
+        create: new_elo_uscf,
+    },
+];
+
+pub fn lookup(name: &str) -> Option<&'static SystemSpec> {
+    SYSTEMS.iter().find(|system| system.name == name)
+}
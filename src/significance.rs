@@ -0,0 +1,206 @@
+use trueskill::cdf;
+
+/// Prediction loss of a system in one game
+#[derive(Clone, Copy, Debug)]
+pub struct GameLoss {
+    /// Whether the higher rated player won, or the game between equals was drawn
+    pub hit: bool,
+    pub squared_error: f32,
+    pub log_loss: f32,
+}
+
+/// Sums of the losses over some games
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LossTotals {
+    pub games: u64,
+    pub hits: u64,
+    pub squared_error: f64,
+    pub log_loss: f64,
+}
+
+impl LossTotals {
+    pub fn add(&mut self, game: &GameLoss) {
+        self.games += 1;
+        self.hits += u64::from(game.hit);
+        self.squared_error += f64::from(game.squared_error);
+        self.log_loss += f64::from(game.log_loss);
+    }
+
+    pub fn merge(&mut self, other: &LossTotals) {
+        self.games += other.games;
+        self.hits += other.hits;
+        self.squared_error += other.squared_error;
+        self.log_loss += other.log_loss;
+    }
+
+    pub fn p_rate(&self) -> f64 {
+        self.hits as f64 / self.games as f64
+    }
+
+    pub fn mse(&self) -> f64 {
+        self.squared_error / self.games as f64
+    }
+
+    pub fn log_loss(&self) -> f64 {
+        self.log_loss / self.games as f64
+    }
+}
+
+/// SplitMix64, enough to draw resamples and reproducible between runs
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed)
+    }
+
+    /// Seed that carries on from here, to save the generator
+    pub fn state(&self) -> u64 {
+        self.0
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in 0..n
+    pub fn below(&mut self, n: usize) -> usize {
+        ((u128::from(self.next()) * n as u128) >> 64) as usize
+    }
+}
+
+/// 95% percentile bootstrap intervals of the p-rate, MSE and log-loss
+pub struct Intervals {
+    pub p_rate: (f64, f64),
+    pub mse: (f64, f64),
+    pub log_loss: (f64, f64),
+}
+
+fn percentiles(mut means: Vec<f64>) -> (f64, f64) {
+    means.sort_by(|a, b| a.total_cmp(b));
+    let last = means.len() - 1;
+    let at = |q: f64| means[(q * last as f64).round() as usize];
+    (at(0.025), at(0.975))
+}
+
+/// Resample the games with replacement and take the spread of the means.
+/// Without games or resamples there is no spread to take.
+pub fn bootstrap(games: &[GameLoss], resamples: usize) -> Option<Intervals> {
+    if games.is_empty() || resamples == 0 {
+        return None;
+    }
+    let mut rng = Rng::new(0x5eed);
+    let n = games.len();
+    let mut p_rates = Vec::with_capacity(resamples);
+    let mut mses = Vec::with_capacity(resamples);
+    let mut log_losses = Vec::with_capacity(resamples);
+    for _ in 0..resamples {
+        let mut hits = 0u64;
+        let mut squared_error = 0.0f64;
+        let mut log_loss = 0.0f64;
+        for _ in 0..n {
+            let game = &games[rng.below(n)];
+            hits += u64::from(game.hit);
+            squared_error += f64::from(game.squared_error);
+            log_loss += f64::from(game.log_loss);
+        }
+        p_rates.push(hits as f64 / n as f64);
+        mses.push(squared_error / n as f64);
+        log_losses.push(log_loss / n as f64);
+    }
+    Some(Intervals {
+        p_rate: percentiles(p_rates),
+        mse: percentiles(mses),
+        log_loss: percentiles(log_losses),
+    })
+}
+
+/// Diebold-Mariano test of equal loss, from the per-game loss differences
+/// of two systems on the same games. Returns the mean difference, the
+/// statistic and its two-sided p-value. Games are taken as independent.
+pub fn diebold_mariano(differences: &[f64]) -> (f64, f64, f64) {
+    let n = differences.len() as f64;
+    let mean = differences.iter().sum::<f64>() / n;
+    let variance = differences
+        .iter()
+        .map(|d| (d - mean).powf(2.0))
+        .sum::<f64>()
+        / (n - 1.0);
+    if variance == 0.0 {
+        // Identical losses, or one system always better by the same amount
+        return match mean {
+            0.0 => (mean, 0.0, 1.0),
+            _ => (mean, mean.signum() * f64::INFINITY, 0.0),
+        };
+    }
+    let statistic = mean / (variance / n).sqrt();
+    let p_value = 2.0 * (1.0 - cdf(statistic.abs()));
+    (mean, statistic, p_value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bootstrap, diebold_mariano, GameLoss};
+
+    fn games() -> Vec<GameLoss> {
+        (0..50)
+            .map(|i| GameLoss {
+                hit: i % 3 != 0,
+                squared_error: (i % 7) as f32 / 10.0,
+                log_loss: (i % 5) as f32 / 4.0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bootstrap_is_reproducible_and_brackets_the_mean() {
+        let games = games();
+        let intervals = bootstrap(&games, 200).unwrap();
+        let again = bootstrap(&games, 200).unwrap();
+        assert_eq!(intervals.mse, again.mse);
+        assert_eq!(intervals.p_rate, again.p_rate);
+
+        let n = games.len() as f32;
+        let mean_mse = f64::from(games.iter().map(|g| g.squared_error).sum::<f32>() / n);
+        let hits = games.iter().filter(|g| g.hit).count();
+        let p_rate = hits as f64 / games.len() as f64;
+        assert!(intervals.mse.0 < mean_mse && mean_mse < intervals.mse.1);
+        assert!(intervals.p_rate.0 < p_rate && p_rate < intervals.p_rate.1);
+        assert!(intervals.log_loss.0 < intervals.log_loss.1);
+    }
+
+    #[test]
+    fn bootstrap_of_equal_games_has_no_spread() {
+        let games = vec![games()[1]; 20];
+        let intervals = bootstrap(&games, 50).unwrap();
+        assert_eq!(intervals.p_rate, (1.0, 1.0));
+        assert!((intervals.mse.1 - intervals.mse.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn bootstrap_needs_games_and_resamples() {
+        assert!(bootstrap(&games(), 0).is_none());
+        assert!(bootstrap(&[], 100).is_none());
+    }
+
+    #[test]
+    fn identical_losses_are_not_significant() {
+        assert_eq!(diebold_mariano(&[0.0; 10]), (0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn diebold_mariano_follows_the_mean_difference() {
+        let (mean, statistic, p_value) = diebold_mariano(&[0.1, -0.1, 0.2, 0.0, 0.3]);
+        assert!((mean - 0.1).abs() < 1e-9);
+        // Standard deviation 0.158, over sqrt(5)
+        assert!((statistic - 0.1 / (0.025f64 / 5.0).sqrt()).abs() < 1e-6);
+        assert!(p_value > 0.1 && p_value < 0.2);
+
+        let (_, statistic, p_value) = diebold_mariano(&[-0.2; 10]);
+        assert!(statistic < 0.0 && p_value == 0.0);
+    }
+}
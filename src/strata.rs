@@ -0,0 +1,194 @@
+use std::collections::BTreeMap;
+
+use significance::{GameLoss, LossTotals};
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Ways to split the games, by the player whose side is being predicted,
+/// as set by the perspective of the stats
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stratum {
+    Rating,
+    PriorGames,
+    Deviation,
+    RatingDifference,
+    Idle,
+}
+
+// Lower bounds of the buckets
+const PRIOR_GAMES: &[i64] = &[0, 1, 2, 5, 10, 20, 50, 100, 200];
+const IDLE_SECONDS: &[i64] = &[
+    0,
+    60 * 60,
+    SECONDS_PER_DAY,
+    7 * SECONDS_PER_DAY,
+    30 * SECONDS_PER_DAY,
+    90 * SECONDS_PER_DAY,
+];
+
+fn duration_name(seconds: i64) -> String {
+    if seconds < SECONDS_PER_DAY {
+        format!("{}h", seconds / (60 * 60))
+    } else {
+        format!("{}d", seconds / SECONDS_PER_DAY)
+    }
+}
+
+impl Stratum {
+    pub const ALL: &'static [Stratum] = &[
+        Stratum::Rating,
+        Stratum::PriorGames,
+        Stratum::Deviation,
+        Stratum::RatingDifference,
+        Stratum::Idle,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stratum::Rating => "rating",
+            Stratum::PriorGames => "prior games",
+            Stratum::Deviation => "RD",
+            Stratum::RatingDifference => "rating difference",
+            Stratum::Idle => "time since last game",
+        }
+    }
+
+    /// Lower bound of the bucket the value falls into
+    fn bucket(&self, value: f64) -> i64 {
+        let floor = |width: f64| (value / width).floor() as i64 * width as i64;
+        let edge = |edges: &[i64]| {
+            *edges
+                .iter()
+                .rev()
+                .find(|edge| value >= **edge as f64)
+                .unwrap_or(&edges[0])
+        };
+        match self {
+            Stratum::Rating => floor(200.0),
+            Stratum::PriorGames => edge(PRIOR_GAMES),
+            Stratum::Deviation => floor(50.0),
+            Stratum::RatingDifference => floor(100.0).clamp(-600, 500),
+            Stratum::Idle => edge(IDLE_SECONDS),
+        }
+    }
+
+    fn label(&self, bucket: i64) -> String {
+        let next = |edges: &[i64]| edges.iter().find(|edge| **edge > bucket).cloned();
+        match self {
+            Stratum::Rating => format!("{}..{}", bucket, bucket + 200),
+            Stratum::PriorGames => match next(PRIOR_GAMES) {
+                Some(next) if next == bucket + 1 => format!("{}", bucket),
+                Some(next) => format!("{}..{}", bucket, next),
+                None => format!("{}+", bucket),
+            },
+            Stratum::Deviation => format!("{}..{}", bucket, bucket + 50),
+            Stratum::RatingDifference if bucket == -600 => "..-500".to_string(),
+            Stratum::RatingDifference if bucket == 500 => "500..".to_string(),
+            Stratum::RatingDifference => format!("{}..{}", bucket, bucket + 100),
+            Stratum::Idle => match next(IDLE_SECONDS) {
+                Some(next) => format!("{}..{}", duration_name(bucket), duration_name(next)),
+                None => format!("{}+", duration_name(bucket)),
+            },
+        }
+    }
+}
+
+/// The prediction stats of one system, split by every stratum
+#[derive(Clone, Debug, Default)]
+pub struct Strata {
+    buckets: BTreeMap<(Stratum, i64), LossTotals>,
+}
+
+impl Strata {
+    /// Account for a game where the stratum has the given value
+    pub fn record(&mut self, stratum: Stratum, value: f64, game: &GameLoss) {
+        self.buckets
+            .entry((stratum, stratum.bucket(value)))
+            .or_default()
+            .add(game);
+    }
+
+    pub fn merge(&mut self, other: &Strata) {
+        for (key, stats) in other.buckets.iter() {
+            self.buckets.entry(*key).or_default().merge(stats);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+}
+
+/// A table per stratum, with a row per bucket and the games, MSE and
+/// log-loss of every system in it
+pub fn tables(heading: &str, names: &[&str], strata: &[&Strata]) -> Vec<String> {
+    let mut lines = vec![];
+    for stratum in Stratum::ALL.iter() {
+        let mut buckets: Vec<i64> = strata
+            .iter()
+            .flat_map(|strata| strata.buckets.keys())
+            .filter(|key| key.0 == *stratum)
+            .map(|key| key.1)
+            .collect();
+        buckets.sort();
+        buckets.dedup();
+        if buckets.is_empty() {
+            continue;
+        }
+
+        let mut header = format!("{:32}", format!("{} by {}:", heading, stratum.name()));
+        for name in names {
+            header += &format!(
+                " {:>9} {:>7} {:>7}",
+                format!("{} games", name),
+                format!("{} MSE", name),
+                format!("{} LL", name)
+            );
+        }
+        lines.push(header);
+
+        for bucket in buckets {
+            let mut row = format!("  {:30}", stratum.label(bucket));
+            for strata in strata {
+                match strata.buckets.get(&(*stratum, bucket)) {
+                    Some(stats) => {
+                        row += &format!(
+                            " {:>9} {:>7.4} {:>7.4}",
+                            stats.games,
+                            stats.mse(),
+                            stats.log_loss()
+                        )
+                    }
+                    None => row += &format!(" {:>9} {:>7} {:>7}", 0, "-", "-"),
+                }
+            }
+            lines.push(row);
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Stratum, SECONDS_PER_DAY};
+
+    fn label(stratum: Stratum, value: f64) -> String {
+        stratum.label(stratum.bucket(value))
+    }
+
+    #[test]
+    fn values_fall_into_their_buckets() {
+        assert_eq!(label(Stratum::Rating, 1499.9), "1400..1600");
+        assert_eq!(label(Stratum::Rating, 1600.0), "1600..1800");
+        assert_eq!(label(Stratum::Deviation, 49.0), "0..50");
+        assert_eq!(label(Stratum::PriorGames, 0.0), "0");
+        assert_eq!(label(Stratum::PriorGames, 3.0), "2..5");
+        assert_eq!(label(Stratum::PriorGames, 5000.0), "200+");
+        assert_eq!(label(Stratum::RatingDifference, -50.0), "-100..0");
+        assert_eq!(label(Stratum::RatingDifference, -900.0), "..-500");
+        assert_eq!(label(Stratum::RatingDifference, 500.0), "500..");
+        assert_eq!(label(Stratum::Idle, 30.0), "0h..1h");
+        assert_eq!(label(Stratum::Idle, 2.0 * SECONDS_PER_DAY as f64), "1d..7d");
+        assert_eq!(label(Stratum::Idle, 365.0 * SECONDS_PER_DAY as f64), "90d+");
+    }
+}
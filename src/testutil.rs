@@ -0,0 +1,26 @@
+//! Fixtures shared by the tests of several modules
+
+use std::path::PathBuf;
+
+use chrono::{TimeZone, Utc};
+use pgn_reader::Outcome;
+
+use {ResultUpdate, TimeControl};
+
+/// Path of a scratch file in the temp dir, unique to the test run. Every
+/// test needs its own name, as the tests run in parallel.
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("rratings-{}-{}", name, std::process::id()))
+}
+
+/// A rated blitz game, the given number of seconds after 1970
+pub fn game(white: &str, black: &str, result: Outcome, seconds: i64) -> ResultUpdate {
+    let mut update = ResultUpdate::new();
+    update.white = white.into();
+    update.black = black.into();
+    update.result = Some(result);
+    update.datetime = Utc.timestamp(seconds, 0);
+    update.rated = true;
+    update.speed = TimeControl::Blitz;
+    update
+}
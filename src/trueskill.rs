@@ -0,0 +1,453 @@
+use std::any::Any;
+use std::f64::consts;
+use std::fmt;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use pgn_reader::Color;
+use ratingsystem::{
+    color_bonus, downcast, non_negative, positive, Parameters, RatingSystem, Settings, StateFields,
+};
+
+// All on the usual rating scale instead of TrueSkill's 25 +- 25/3
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrueSkillParams {
+    pub initial_sigma: f32,
+    /// Performance spread of a single game
+    pub beta: f32,
+    /// Spread added before every game
+    pub tau: f32,
+    /// Variance added per day without games
+    pub daily_variance: f32,
+    pub draw_probability: f32,
+    /// Rating points white is stronger by
+    pub white_advantage: f32,
+}
+
+impl TrueSkillParams {
+    /// Performance difference below which a game is drawn, such that
+    /// equal players draw with draw_probability
+    fn draw_margin(&self) -> f64 {
+        let p = f64::from(self.draw_probability);
+        inverse_cdf((p + 1.0) / 2.0) * consts::SQRT_2 * f64::from(self.beta)
+    }
+}
+
+impl Default for TrueSkillParams {
+    fn default() -> TrueSkillParams {
+        TrueSkillParams {
+            initial_sigma: 350.0,
+            beta: 200.0,
+            tau: 5.0,
+            daily_variance: 10.0,
+            draw_probability: 0.05,
+            white_advantage: 0.0,
+        }
+    }
+}
+
+impl Parameters for TrueSkillParams {
+    fn set(&mut self, key: &str, value: f32) -> Result<(), String> {
+        match key {
+            "initial_sigma" => self.initial_sigma = positive(key, value)?,
+            "beta" => self.beta = positive(key, value)?,
+            "tau" => self.tau = non_negative(key, value)?,
+            "daily_variance" => self.daily_variance = non_negative(key, value)?,
+            "draw_probability" if (0.0..1.0).contains(&value) => self.draw_probability = value,
+            "draw_probability" => {
+                return Err(format!(
+                    "draw_probability must be from 0 to below 1, not {}",
+                    value
+                ))
+            }
+            "white_advantage" => self.white_advantage = value,
+            _ => return Err(format!("Unknown TrueSkill parameter {}", key)),
+        }
+        Ok(())
+    }
+
+    fn values(&self) -> Settings {
+        let mut values = Settings::new();
+        values.insert("initial_sigma".to_string(), self.initial_sigma);
+        values.insert("beta".to_string(), self.beta);
+        values.insert("tau".to_string(), self.tau);
+        values.insert("daily_variance".to_string(), self.daily_variance);
+        values.insert("draw_probability".to_string(), self.draw_probability);
+        values.insert("white_advantage".to_string(), self.white_advantage);
+        values
+    }
+}
+
+fn pdf(x: f64) -> f64 {
+    (-x * x / 2.0).exp() / (2.0 * consts::PI).sqrt()
+}
+
+// Complementary error function with a relative error below 1.2e-7
+// everywhere, so the tails stay accurate (Numerical Recipes erfcc)
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let poly = -1.265_512_23
+        + t * (1.000_023_68
+            + t * (0.374_091_96
+                + t * (0.096_784_18
+                    + t * (-0.186_288_06
+                        + t * (0.278_868_07
+                            + t * (-1.135_203_98
+                                + t * (1.488_515_87 + t * (-0.822_152_23 + t * 0.170_872_77))))))));
+    let r = t * (-z * z + poly).exp();
+    if x >= 0.0 {
+        r
+    } else {
+        2.0 - r
+    }
+}
+
+pub fn cdf(x: f64) -> f64 {
+    0.5 * erfc(-x / consts::SQRT_2)
+}
+
+// Acklam's rational approximation, good to about 1e-9
+fn inverse_cdf(p: f64) -> f64 {
+    const A: [f64; 6] = [
+        -3.969_683_028_665_376e1,
+        2.209_460_984_245_205e2,
+        -2.759_285_104_469_687e2,
+        1.383_577_518_672_69e2,
+        -3.066_479_806_614_716e1,
+        2.506_628_277_459_239,
+    ];
+    const B: [f64; 5] = [
+        -5.447_609_879_822_406e1,
+        1.615_858_368_580_409e2,
+        -1.556_989_798_598_866e2,
+        6.680_131_188_771_972e1,
+        -1.328_068_155_288_572e1,
+    ];
+    const C: [f64; 6] = [
+        -7.784_894_002_430_293e-3,
+        -3.223_964_580_411_365e-1,
+        -2.400_758_277_161_838,
+        -2.549_732_539_343_734,
+        4.374_664_141_464_968,
+        2.938_163_982_698_783,
+    ];
+    const D: [f64; 4] = [
+        7.784_695_709_041_462e-3,
+        3.224_671_290_700_398e-1,
+        2.445_134_137_142_996,
+        3.754_408_661_907_416,
+    ];
+    const LOW: f64 = 0.024_25;
+
+    let tail = |q: f64| {
+        (((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5])
+            / ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.0)
+    };
+    if p < LOW {
+        tail((-2.0 * p.ln()).sqrt())
+    } else if p > 1.0 - LOW {
+        -tail((-2.0 * (1.0 - p).ln()).sqrt())
+    } else {
+        let q = p - 0.5;
+        let r = q * q;
+        (((((A[0] * r + A[1]) * r + A[2]) * r + A[3]) * r + A[4]) * r + A[5]) * q
+            / (((((B[0] * r + B[1]) * r + B[2]) * r + B[3]) * r + B[4]) * r + 1.0)
+    }
+}
+
+// Mean and variance corrections of the truncated Gaussians, for a win
+// and a draw, with t the scaled performance difference and e the scaled
+// draw margin
+fn v_win(t: f64, e: f64) -> f64 {
+    let x = t - e;
+    let denominator = cdf(x);
+    if denominator < 1e-300 {
+        -x
+    } else {
+        pdf(x) / denominator
+    }
+}
+
+fn w_win(t: f64, e: f64) -> f64 {
+    let v = v_win(t, e);
+    (v * (v + t - e)).clamp(0.0, 1.0)
+}
+
+fn v_draw(t: f64, e: f64) -> f64 {
+    let denominator = cdf(e - t) - cdf(-e - t);
+    if denominator < 1e-300 {
+        if t < 0.0 {
+            -t - e
+        } else {
+            -t + e
+        }
+    } else {
+        (pdf(-e - t) - pdf(e - t)) / denominator
+    }
+}
+
+fn w_draw(t: f64, e: f64) -> f64 {
+    let denominator = cdf(e - t) - cdf(-e - t);
+    if denominator < 1e-300 {
+        return 1.0;
+    }
+    let v = v_draw(t, e);
+    (v * v + ((e - t) * pdf(e - t) + (e + t) * pdf(e + t)) / denominator).clamp(0.0, 1.0)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrueSkillRating {
+    pub mu: f32,
+    pub sigma: f32,
+    params: Arc<TrueSkillParams>,
+}
+
+impl fmt::Display for TrueSkillRating {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:.1},{:.1}", self.mu, self.sigma)
+    }
+}
+
+impl TrueSkillRating {
+    pub fn new(params: Arc<TrueSkillParams>) -> TrueSkillRating {
+        TrueSkillRating {
+            mu: 1500.0,
+            sigma: params.initial_sigma,
+            params,
+        }
+    }
+
+    fn calc_days(old: &DateTime<Utc>, now: &DateTime<Utc>) -> f32 {
+        let duration = *now - *old;
+        let seconds = duration.num_seconds() as f64;
+        let days = seconds / (24.0 * 60.0 * 60.0);
+        days as f32
+    }
+
+    /// Variance of the skill right before a game, after the given days
+    /// of inactivity
+    fn pre_game_variance(&self, days: f32) -> f64 {
+        let params = &self.params;
+        let variance = self.sigma.powf(2.0) + params.tau.powf(2.0) + params.daily_variance * days;
+        f64::from(variance.min(params.initial_sigma.powf(2.0)))
+    }
+
+    // mu with the advantage of the color
+    fn color_mu(&self, color: Color) -> f32 {
+        self.mu + color_bonus(color, self.params.white_advantage)
+    }
+
+    /// Win, draw and loss probabilities against the opponent, playing the
+    /// given color
+    fn outcome_probabilities(
+        &self,
+        color: Color,
+        variance: f64,
+        opponent: &TrueSkillRating,
+        opponent_variance: f64,
+    ) -> [f32; 3] {
+        let beta = f64::from(self.params.beta);
+        let c = (2.0 * beta * beta + variance + opponent_variance).sqrt();
+        let diff = f64::from(self.color_mu(color) - opponent.mu);
+        let e = self.params.draw_margin();
+        let win = cdf((diff - e) / c);
+        let loss = cdf((-diff - e) / c);
+        let draw = (1.0 - win - loss).max(0.0);
+        [win as f32, draw as f32, loss as f32]
+    }
+}
+
+impl RatingSystem for TrueSkillRating {
+    fn rating(&self) -> f32 {
+        self.mu
+    }
+
+    fn deviation(&self) -> f32 {
+        self.sigma
+    }
+
+    fn expect(
+        &self,
+        color: Color,
+        old_time: &DateTime<Utc>,
+        result_time: &DateTime<Utc>,
+        opponent: &dyn RatingSystem,
+        opponent_time: &DateTime<Utc>,
+    ) -> f32 {
+        let [win, draw, _] = self
+            .expect_outcomes(color, old_time, result_time, opponent, opponent_time)
+            .unwrap();
+        win + 0.5 * draw
+    }
+
+    fn expect_outcomes(
+        &self,
+        color: Color,
+        old_time: &DateTime<Utc>,
+        result_time: &DateTime<Utc>,
+        opponent: &dyn RatingSystem,
+        opponent_time: &DateTime<Utc>,
+    ) -> Option<[f32; 3]> {
+        let opponent = downcast::<TrueSkillRating>(opponent);
+        let variance = self.pre_game_variance(TrueSkillRating::calc_days(old_time, result_time));
+        let opponent_variance =
+            opponent.pre_game_variance(TrueSkillRating::calc_days(opponent_time, result_time));
+        Some(self.outcome_probabilities(color, variance, opponent, opponent_variance))
+    }
+
+    fn update_with_result(
+        &mut self,
+        color: Color,
+        score: f32,
+        old_time: &DateTime<Utc>,
+        result_time: &DateTime<Utc>,
+        opponent: &dyn RatingSystem,
+        opponent_time: &DateTime<Utc>,
+    ) {
+        let opponent = downcast::<TrueSkillRating>(opponent);
+        let variance = self.pre_game_variance(TrueSkillRating::calc_days(old_time, result_time));
+        let opponent_variance =
+            opponent.pre_game_variance(TrueSkillRating::calc_days(opponent_time, result_time));
+
+        let beta = f64::from(self.params.beta);
+        let c = (2.0 * beta * beta + variance + opponent_variance).sqrt();
+        let e = self.params.draw_margin() / c;
+        let diff = f64::from(self.color_mu(color) - opponent.mu) / c;
+
+        // Move towards the observed side of the performance difference
+        let (v, w) = if score > 0.75 {
+            (v_win(diff, e), w_win(diff, e))
+        } else if score < 0.25 {
+            (-v_win(-diff, e), w_win(-diff, e))
+        } else {
+            (v_draw(diff, e), w_draw(diff, e))
+        };
+
+        let mu = f64::from(self.mu) + variance / c * v;
+        let new_variance = variance * (1.0 - variance / (c * c) * w);
+        self.mu = mu as f32;
+        self.sigma = new_variance.sqrt() as f32;
+    }
+
+    fn serialize(&self) -> String {
+        format!("{},{}", self.mu, self.sigma)
+    }
+
+    fn restore(&mut self, state: &str) -> Result<(), String> {
+        let mut fields = StateFields::new(state);
+        self.mu = fields.next()?;
+        self.sigma = fields.next()?;
+        fields.end()
+    }
+
+    fn box_clone(&self) -> Box<dyn RatingSystem> {
+        Box::new(self.clone())
+    }
+
+    fn copy_from(&mut self, other: &dyn RatingSystem) {
+        self.clone_from(downcast::<TrueSkillRating>(other));
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::{TimeZone, Utc};
+    use pgn_reader::Color;
+
+    use super::{v_draw, v_win, w_draw, w_win, TrueSkillParams, TrueSkillRating};
+    use ratingsystem::RatingSystem;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-6,
+            "{} vs {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn truncated_gaussians_match_known_values() {
+        assert_close(v_win(0.0, 0.0), 0.797_884_6);
+        // 2 / pi
+        assert_close(w_win(0.0, 0.0), std::f64::consts::FRAC_2_PI);
+        assert_close(v_win(1.0, 0.0), 0.287_600_0);
+        assert_close(w_win(1.0, 0.0), 0.370_313_7);
+        assert_close(v_win(-2.0, 0.5), 2.822_744_8);
+        assert_close(w_win(-2.0, 0.5), 0.911_026_2);
+        assert_close(v_draw(0.0, 1.0), 0.0);
+        assert_close(w_draw(0.0, 1.0), 0.708_874_9);
+        assert_close(v_draw(0.5, 1.0), -0.356_272_9);
+        assert_close(w_draw(0.5, 1.0), 0.719_751_8);
+    }
+
+    // TrueSkill's own scale and defaults, with a cap that doesn't bind
+    fn reference() -> (TrueSkillRating, TrueSkillRating) {
+        let params = Arc::new(TrueSkillParams {
+            initial_sigma: 100.0,
+            beta: 25.0 / 6.0,
+            tau: 25.0 / 300.0,
+            daily_variance: 0.0,
+            draw_probability: 0.1,
+            white_advantage: 0.0,
+        });
+        let player = TrueSkillRating {
+            mu: 25.0,
+            sigma: 25.0 / 3.0,
+            params,
+        };
+        (player.clone(), player)
+    }
+
+    fn rate(score: f32) -> (TrueSkillRating, TrueSkillRating) {
+        let (mut white, mut black) = reference();
+        let time = Utc.timestamp(0, 0);
+        let white_before = white.clone();
+        white.update_with_result(Color::White, score, &time, &time, &black, &time);
+        black.update_with_result(
+            Color::Black,
+            1.0 - score,
+            &time,
+            &time,
+            &white_before,
+            &time,
+        );
+        (white, black)
+    }
+
+    // The reference implementation (the trueskill package) gives
+    // 29.396 +- 7.171 and 20.604 +- 7.171 for a win, 25.000 +- 6.458 for
+    // a draw
+    #[test]
+    fn matches_the_reference_implementation() {
+        let (winner, loser) = rate(1.0);
+        assert!((winner.mu - 29.396).abs() < 1e-3 && (winner.sigma - 7.171).abs() < 1e-3);
+        assert!((loser.mu - 20.604).abs() < 1e-3 && (loser.sigma - 7.171).abs() < 1e-3);
+
+        let (white, black) = rate(0.5);
+        assert!((white.mu - 25.0).abs() < 1e-4 && (white.sigma - 6.458).abs() < 1e-3);
+        assert_eq!((white.mu, white.sigma), (black.mu, black.sigma));
+    }
+
+    #[test]
+    fn outcome_probabilities_add_up() {
+        let (mut white, black) = reference();
+        // Certain skills draw exactly as often as the parameter says
+        let [win, draw, loss] = white.outcome_probabilities(Color::White, 0.0, &black, 0.0);
+        assert!((win + draw + loss - 1.0).abs() < 1e-6);
+        assert!((draw - 0.1).abs() < 1e-4);
+        assert_eq!(win, loss);
+
+        white.mu = 30.0;
+        let [win, draw, loss] = white.outcome_probabilities(Color::White, 50.0, &black, 70.0);
+        assert!((win + draw + loss - 1.0).abs() < 1e-6);
+        assert!(win > loss && draw > 0.0);
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::BTreeMap;
+use std::slice;
+
+use pgn_reader::Color;
+use playerdb::{Player, PlayerNames};
+use ratingsystem::SystemInstance;
+
+use super::{GameSink, ResultUpdate, TimeControl};
+
+#[derive(Default)]
+struct Pool {
+    names: PlayerNames,
+    players: Vec<Player>,
+    // Games with rating diff headers
+    games: u64,
+    // Games where both players had the same rating as on lichess
+    in_sync: u64,
+    // In sync games where a rating diff came out different
+    mismatched: u64,
+    // Scratch copies of the players before the game, reused between games
+    white_before: Option<Player>,
+    black_before: Option<Player>,
+}
+
+/// Copy the player into the scratch player, creating it on first use
+fn copy_into<'a>(scratch: &'a mut Option<Player>, player: &Player) -> &'a Player {
+    match scratch {
+        Some(ref mut copy) => copy.copy_from(player),
+        None => *scratch = Some(player.clone()),
+    }
+    scratch.as_ref().unwrap()
+}
+
+impl Pool {
+    fn player_id(&mut self, update: &ResultUpdate, name: &str, system: &SystemInstance) -> usize {
+        let id = self.names.intern(name) as usize;
+        if id == self.players.len() {
+            self.players
+                .push(Player::new(&update.datetime, slice::from_ref(system)));
+        }
+        id
+    }
+}
+
+/// Replays the games with a single rating system, like lichess does: both
+/// players are updated from their ratings before the game. Whenever both
+/// players have the rating the game was played at, the rating changes are
+/// compared against the ones lichess recorded.
+pub struct Verifier {
+    system: SystemInstance,
+    pools: BTreeMap<TimeControl, Pool>,
+}
+
+impl Verifier {
+    pub fn new(system: SystemInstance) -> Verifier {
+        Verifier {
+            system,
+            pools: BTreeMap::new(),
+        }
+    }
+
+    /// Agreement with lichess since the last reset, one line per pool
+    pub fn get_stats(&self) -> String {
+        let mut lines = vec![];
+        for (speed, pool) in self.pools.iter() {
+            if pool.games == 0 {
+                continue;
+            }
+            let mut line = format!(
+                "{}: {} games with rating diffs, {:.3}% in sync",
+                speed.name(),
+                pool.games,
+                100.0 * pool.in_sync as f64 / pool.games as f64
+            );
+            if pool.in_sync > 0 {
+                line += &format!(
+                    ", {:.3}% of those mismatched",
+                    100.0 * pool.mismatched as f64 / pool.in_sync as f64
+                );
+            }
+            lines.push(line);
+        }
+        if lines.is_empty() {
+            return "No games with rating diffs".to_string();
+        }
+        lines.join("\n")
+    }
+
+    pub fn stats_reset(&mut self) {
+        for pool in self.pools.values_mut() {
+            pool.games = 0;
+            pool.in_sync = 0;
+            pool.mismatched = 0;
+        }
+    }
+}
+
+impl GameSink for Verifier {
+    fn add_game(&mut self, update: ResultUpdate) {
+        let system = &self.system;
+        let pool = self.pools.entry(update.speed.clone()).or_default();
+        let white = pool.player_id(&update, &update.white, system);
+        let black = pool.player_id(&update, &update.black, system);
+        // Can't happen on lichess
+        if white == black {
+            return;
+        }
+
+        let white_before = copy_into(&mut pool.white_before, &pool.players[white]);
+        let black_before = copy_into(&mut pool.black_before, &pool.players[black]);
+        let result = update.result.unwrap();
+        pool.players[white].update_with_result(
+            Color::White,
+            &result,
+            &update.datetime,
+            black_before,
+        );
+        pool.players[black].update_with_result(
+            Color::Black,
+            &result,
+            &update.datetime,
+            white_before,
+        );
+
+        let (white_diff, black_diff) = match (update.white_rating_diff, update.black_rating_diff) {
+            (Some(white_diff), Some(black_diff)) => (white_diff, black_diff),
+            _ => return,
+        };
+        pool.games += 1;
+        let int_rating = |player: &Player| player.ratings[0].int_rating();
+        let (white_rating, black_rating) = match (update.white_rating, update.black_rating) {
+            (Some(white_rating), Some(black_rating)) => (white_rating, black_rating),
+            _ => return,
+        };
+        if int_rating(white_before) != white_rating || int_rating(black_before) != black_rating {
+            return;
+        }
+        pool.in_sync += 1;
+        if int_rating(&pool.players[white]) - white_rating != white_diff
+            || int_rating(&pool.players[black]) - black_rating != black_diff
+        {
+            pool.mismatched += 1;
+        }
+    }
+
+    fn status(&self) -> String {
+        let players: usize = self.pools.values().map(|pool| pool.players.len()).sum();
+        format!(", {} players", players)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pgn_reader::{Color, Outcome};
+
+    use super::Verifier;
+    use ratingsystem::{lookup, Settings};
+    use testutil;
+    use {GameSink, ResultUpdate};
+
+    fn game(
+        white: &str,
+        black: &str,
+        ratings: (i32, i32),
+        diffs: Option<(i32, i32)>,
+    ) -> ResultUpdate {
+        let white_wins = Outcome::Decisive {
+            winner: Color::White,
+        };
+        let mut update = testutil::game(white, black, white_wins, 0);
+        update.white_rating = Some(ratings.0);
+        update.black_rating = Some(ratings.1);
+        update.white_rating_diff = diffs.map(|diffs| diffs.0);
+        update.black_rating_diff = diffs.map(|diffs| diffs.1);
+        update
+    }
+
+    #[test]
+    fn counts_matching_and_mismatching_games() {
+        let system = lookup("LG")
+            .unwrap()
+            .instantiate("LG", &Settings::new())
+            .unwrap();
+        let mut verifier = Verifier::new(system);
+        assert_eq!(verifier.get_stats(), "No games with rating diffs");
+
+        // New players go to 1662 and 1337, as on lichess
+        verifier.add_game(game("a", "b", (1500, 1500), Some((162, -163))));
+        verifier.add_game(game("c", "d", (1500, 1500), Some((10, -10))));
+        // a isn't at 1400, so this one isn't in sync
+        verifier.add_game(game("a", "b", (1400, 1337), Some((5, -5))));
+        verifier.add_game(game("c", "d", (1662, 1337), None));
+        assert_eq!(
+            verifier.get_stats(),
+            "blitz: 3 games with rating diffs, 66.667% in sync, 50.000% of those mismatched"
+        );
+
+        verifier.stats_reset();
+        assert_eq!(verifier.get_stats(), "No games with rating diffs");
+    }
+}
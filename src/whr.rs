@@ -0,0 +1,451 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::f64::consts;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+
+use pgn_reader::{Color, Outcome};
+use playerdb::{report_path, PlayerNames, SystemStats};
+
+use super::{GameSink, ResultUpdate, TimeControl};
+
+// Natural rating units per Elo point
+const ELO_SCALE: f64 = consts::LN_10 / 400.0;
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+#[derive(Clone, Debug)]
+pub struct WhrParams {
+    /// Variance of the Wiener process, in Elo^2 per day
+    pub w2: f64,
+    /// Virtual wins and losses against a 1500 player on the first day
+    pub prior_games: f64,
+    /// Newton passes over the players of every new file
+    pub iterations: usize,
+}
+
+impl WhrParams {
+    /// A zero variance pins every rating in place and divides by zero, and
+    /// negative virtual games push ratings apart
+    fn check(&self) -> Result<(), String> {
+        if self.w2.is_nan() || self.w2 <= 0.0 {
+            return Err(format!("w2 must be positive, not {}", self.w2));
+        }
+        if self.prior_games.is_nan() || self.prior_games < 0.0 {
+            return Err(format!(
+                "prior-games must not be negative, not {}",
+                self.prior_games
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn logistic(x: f64) -> f64 {
+    1.0 / (1.0 + (-x).exp())
+}
+
+/// Solve H x = b for a symmetric tridiagonal H, given by its diagonal and
+/// the diagonal next to it (Thomas algorithm)
+fn solve_tridiagonal(diag: &[f64], off: &[f64], b: &[f64]) -> Vec<f64> {
+    let n = diag.len();
+    let mut c = vec![0.0; n];
+    let mut x = vec![0.0; n];
+    if n > 1 {
+        c[0] = off[0] / diag[0];
+    }
+    x[0] = b[0] / diag[0];
+    for i in 1..n {
+        let m = diag[i] - off[i - 1] * c[i - 1];
+        if i < n - 1 {
+            c[i] = off[i] / m;
+        }
+        x[i] = (b[i] - off[i - 1] * x[i - 1]) / m;
+    }
+    for i in (0..n - 1).rev() {
+        x[i] -= c[i] * x[i + 1];
+    }
+    x
+}
+
+struct Game {
+    opponent: usize,
+    // Day of the game, in the days of the opponent
+    day: i64,
+    score: f64,
+}
+
+/// All games of a player on one day, played at one rating
+struct Day {
+    day: i64,
+    // In natural units, 0 is 1500 Elo
+    r: f64,
+    games: Vec<Game>,
+}
+
+#[derive(Default)]
+struct Player {
+    days: Vec<Day>,
+}
+
+// A game since the last refit, for the hindsight stats
+struct NewGame {
+    white: usize,
+    black: usize,
+    day: i64,
+    score: f64,
+}
+
+#[derive(Default)]
+struct Pool {
+    names: PlayerNames,
+    players: Vec<Player>,
+    new_games: Vec<NewGame>,
+    // Players with games since the last refit
+    touched: BTreeSet<usize>,
+}
+
+impl Pool {
+    fn player_id(&mut self, name: &str) -> usize {
+        let id = self.names.intern(name) as usize;
+        if id == self.players.len() {
+            self.players.push(Player::default());
+        }
+        id
+    }
+
+    /// Index of the given day in the player's history. A new day is
+    /// inserted in date order, starting from the rating of the day before
+    /// it, or after it if it is the first.
+    fn day_index(&mut self, player: usize, day: i64) -> usize {
+        let days = &mut self.players[player].days;
+        match days.binary_search_by_key(&day, |d| d.day) {
+            Ok(index) => index,
+            Err(index) => {
+                let r = match index {
+                    0 => days.first().map_or(0.0, |next| next.r),
+                    _ => days[index - 1].r,
+                };
+                days.insert(
+                    index,
+                    Day {
+                        day,
+                        r,
+                        games: vec![],
+                    },
+                );
+                index
+            }
+        }
+    }
+
+    /// Rating of a player on one of the days they played
+    fn rating_on(&self, player: usize, day: i64) -> f64 {
+        let days = &self.players[player].days;
+        let index = days.binary_search_by_key(&day, |d| d.day).unwrap();
+        days[index].r
+    }
+
+    fn add_game(&mut self, update: &ResultUpdate) {
+        // Can't happen on lichess
+        if update.white == update.black {
+            return;
+        }
+        let white = self.player_id(&update.white);
+        let black = self.player_id(&update.black);
+        let day = update.datetime.timestamp().div_euclid(SECONDS_PER_DAY);
+        let white_day = self.day_index(white, day);
+        let black_day = self.day_index(black, day);
+        let score = match update.result {
+            Some(Outcome::Decisive {
+                winner: Color::White,
+            }) => 1.0,
+            Some(Outcome::Decisive {
+                winner: Color::Black,
+            }) => 0.0,
+            _ => 0.5,
+        };
+
+        self.players[white].days[white_day].games.push(Game {
+            opponent: black,
+            day,
+            score,
+        });
+        self.players[black].days[black_day].games.push(Game {
+            opponent: white,
+            day,
+            score: 1.0 - score,
+        });
+        self.new_games.push(NewGame {
+            white,
+            black,
+            day,
+            score,
+        });
+        self.touched.insert(white);
+        self.touched.insert(black);
+    }
+
+    /// One Newton step on the whole history of a player, keeping the
+    /// opponents fixed
+    fn newton(&mut self, id: usize, params: &WhrParams) {
+        let days = &self.players[id].days;
+        let n = days.len();
+        let mut gradient = vec![0.0; n];
+        let mut diag = vec![0.0; n];
+        let mut off = vec![0.0; n.saturating_sub(1)];
+
+        for (i, day) in days.iter().enumerate() {
+            for game in day.games.iter() {
+                let opponent_r = self.rating_on(game.opponent, game.day);
+                let p = logistic(day.r - opponent_r);
+                gradient[i] += game.score - p;
+                diag[i] -= p * (1.0 - p);
+            }
+        }
+
+        let p = logistic(days[0].r);
+        gradient[0] += params.prior_games * (1.0 - 2.0 * p);
+        diag[0] -= 2.0 * params.prior_games * p * (1.0 - p);
+
+        let w2 = params.w2 * ELO_SCALE * ELO_SCALE;
+        for i in 0..n.saturating_sub(1) {
+            let variance = w2 * (days[i + 1].day - days[i].day) as f64;
+            let pull = (days[i + 1].r - days[i].r) / variance;
+            gradient[i] += pull;
+            gradient[i + 1] -= pull;
+            diag[i] -= 1.0 / variance;
+            diag[i + 1] -= 1.0 / variance;
+            off[i] = 1.0 / variance;
+        }
+
+        let step = solve_tridiagonal(&diag, &off, &gradient);
+        for (day, step) in self.players[id].days.iter_mut().zip(step.iter()) {
+            day.r -= step;
+        }
+    }
+
+    fn refit(&mut self, params: &WhrParams) {
+        let touched: Vec<_> = self.touched.iter().cloned().collect();
+        for _ in 0..params.iterations {
+            for id in touched.iter() {
+                self.newton(*id, params);
+            }
+        }
+        self.touched.clear();
+    }
+
+    /// How well the fitted ratings explain the games since the last reset
+    fn stats(&self) -> SystemStats {
+        let mut stats = SystemStats::default();
+        for game in self.new_games.iter() {
+            let white_r = self.rating_on(game.white, game.day);
+            let black_r = self.rating_on(game.black, game.day);
+            let expected_score = logistic(white_r - black_r) as f32;
+            let score = game.score as f32;
+
+            let guess = if white_r > black_r {
+                1.0f32
+            } else if white_r < black_r {
+                0.0f32
+            } else {
+                0.5f32
+            };
+            stats.guess += 1;
+            if (score - guess).abs() < 0.5f32 {
+                stats.predicted += 1
+            }
+
+            stats.record(score, expected_score);
+        }
+        stats
+    }
+}
+
+/// Whole-History Rating: every player's rating is a Wiener process over
+/// time, fitted to all their games at once. Being fitted in hindsight, it
+/// shows how well the games could be explained at best.
+pub struct Whr {
+    params: WhrParams,
+    pools: BTreeMap<TimeControl, Pool>,
+}
+
+impl Whr {
+    pub fn new(params: WhrParams) -> io::Result<Whr> {
+        params
+            .check()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(Whr {
+            params,
+            pools: BTreeMap::new(),
+        })
+    }
+
+    /// Refit the players of the games added since the last refit
+    pub fn refit(&mut self) {
+        for pool in self.pools.values_mut() {
+            pool.refit(&self.params);
+        }
+    }
+
+    /// Fit of the games since the last reset, one line per pool
+    pub fn get_stats(&self) -> String {
+        let mut lines = vec![];
+        for (speed, pool) in self.pools.iter() {
+            if pool.new_games.is_empty() {
+                continue;
+            }
+            let stats = pool.stats();
+            let pred_rate = 100.0 * stats.predicted as f64 / stats.guess as f64;
+            lines.push(format!(
+                "{}: {:.3}% WHR p-rate, {:.4} WHR MSE, {:.4} WHR log-loss",
+                speed.name(),
+                pred_rate,
+                stats.mse(),
+                stats.log_loss()
+            ));
+        }
+        lines.join("\n")
+    }
+
+    pub fn stats_reset(&mut self) {
+        for pool in self.pools.values_mut() {
+            pool.new_games.clear();
+        }
+    }
+
+    /// Write the current rating of every player, a report per pool
+    pub fn dump_report(&self, report_filename: &str) {
+        for (speed, pool) in self.pools.iter() {
+            let mut ratings: Vec<_> = pool
+                .names
+                .iter()
+                .zip(pool.players.iter())
+                .filter_map(|(name, player)| {
+                    let last = player.days.last()?;
+                    Some((name, 1500.0 + last.r / ELO_SCALE, player.days.len()))
+                })
+                .collect();
+            ratings.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+
+            let mut file = File::create(report_path(report_filename, speed)).unwrap();
+            for (name, rating, days) in ratings {
+                writeln!(file, "{},{:.1},{}", name, rating, days).unwrap();
+            }
+        }
+    }
+}
+
+impl GameSink for Whr {
+    fn add_game(&mut self, update: ResultUpdate) {
+        self.pools
+            .entry(update.speed.clone())
+            .or_default()
+            .add_game(&update);
+    }
+
+    fn status(&self) -> String {
+        let players: usize = self.pools.values().map(|pool| pool.players.len()).sum();
+        format!(", {} players", players)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64;
+    use std::io;
+
+    use pgn_reader::{Color, Outcome};
+
+    use super::{GameSink, Whr, WhrParams, ELO_SCALE, SECONDS_PER_DAY};
+    use testutil;
+    use {ResultUpdate, TimeControl};
+
+    fn game(white: &str, black: &str, result: Outcome, day: i64) -> ResultUpdate {
+        testutil::game(white, black, result, day * SECONDS_PER_DAY)
+    }
+
+    fn wins(color: Color) -> Outcome {
+        Outcome::Decisive { winner: color }
+    }
+
+    fn new_whr(prior_games: f64) -> Whr {
+        Whr::new(WhrParams {
+            w2: 14.0,
+            prior_games,
+            iterations: 50,
+        })
+        .unwrap()
+    }
+
+    fn rating(whr: &Whr, name: &str, day: i64) -> f64 {
+        let pool = &whr.pools[&TimeControl::Blitz];
+        let id = pool.names.iter().position(|known| known == name).unwrap();
+        1500.0 + pool.rating_on(id, day) / ELO_SCALE
+    }
+
+    #[test]
+    fn two_players_converge_to_the_score_gap() {
+        let mut whr = new_whr(0.0);
+        for result in [
+            wins(Color::White),
+            wins(Color::White),
+            wins(Color::Black),
+            wins(Color::White),
+        ]
+        .iter()
+        {
+            whr.add_game(game("a", "b", *result, 1));
+        }
+        whr.refit();
+
+        // Scoring 3 out of 4 is odds of 3 to 1
+        let gap = 400.0 * 3f64.log10();
+        let fitted = rating(&whr, "a", 1) - rating(&whr, "b", 1);
+        assert!((fitted - gap).abs() < 0.01, "{} vs {}", fitted, gap);
+    }
+
+    #[test]
+    fn earlier_games_insert_days_in_order() {
+        let mut whr = new_whr(1.0);
+        whr.add_game(game("a", "b", wins(Color::White), 10));
+        whr.add_game(game("a", "c", wins(Color::White), 5));
+        whr.add_game(game("c", "a", Outcome::Draw, 7));
+        whr.refit();
+
+        let pool = &whr.pools[&TimeControl::Blitz];
+        let days: Vec<_> = pool.players[0].days.iter().map(|d| d.day).collect();
+        assert_eq!(days, vec![5, 7, 10]);
+        for day in days {
+            assert!(rating(&whr, "a", day).is_finite());
+        }
+        assert!(rating(&whr, "a", 10) > rating(&whr, "b", 10));
+    }
+
+    #[test]
+    fn parameters_out_of_range_are_rejected() {
+        for (w2, prior_games) in [(0.0, 1.0), (-1.0, 1.0), (f64::NAN, 1.0), (14.0, -1.0)].iter() {
+            let params = WhrParams {
+                w2: *w2,
+                prior_games: *prior_games,
+                iterations: 1,
+            };
+            let err = Whr::new(params).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+        assert!(Whr::new(WhrParams {
+            w2: 14.0,
+            prior_games: 0.0,
+            iterations: 0,
+        })
+        .is_ok());
+    }
+
+    #[test]
+    fn games_against_oneself_add_no_players() {
+        let mut whr = new_whr(1.0);
+        whr.add_game(game("a", "a", Outcome::Draw, 1));
+        whr.add_game(game("b", "c", Outcome::Draw, 1));
+        assert_eq!(whr.pools[&TimeControl::Blitz].players.len(), 2);
+    }
+}